@@ -1,5 +1,21 @@
 fn main() {
-    println!("cargo:rustc-link-search=native=/path/to/torch/library");
-    println!("cargo:rustc-link-lib=torch");
-    println!("cargo:rustc-link-lib=dylib=torch");
+    #[cfg(feature = "torch")]
+    {
+        println!("cargo:rustc-link-search=native=/path/to/torch/library");
+        println!("cargo:rustc-link-lib=torch");
+        println!("cargo:rustc-link-lib=dylib=torch");
+    }
+
+    #[cfg(feature = "grpc")]
+    {
+        // Use the vendored protoc so building doesn't need protobuf installed.
+        if std::env::var_os("PROTOC").is_none() {
+            std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        }
+        // Only the server side is served, and the generated client needs the 2021 prelude.
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/grpc_predict_v2.proto"], &["proto"])
+            .unwrap();
+    }
 }
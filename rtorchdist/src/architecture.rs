@@ -0,0 +1,185 @@
+/*
+Maps weight files to the tch::vision network they were exported from.
+ */
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "torch")]
+use tch::nn::{ModuleT, Path};
+#[cfg(feature = "torch")]
+use tch::vision;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Architecture {
+    Resnet18,
+    Resnet34,
+    Resnet50,
+    Densenet121,
+    Vgg13,
+    Vgg16,
+    Vgg19,
+    #[serde(rename = "squeezenet1_0")]
+    Squeezenet1_0,
+    #[serde(rename = "squeezenet1_1")]
+    Squeezenet1_1,
+    Alexnet,
+    MobilenetV2,
+    InceptionV3,
+}
+
+impl Architecture {
+    pub const ALL: [Architecture; 12] = [
+        Architecture::Resnet18,
+        Architecture::Resnet34,
+        Architecture::Resnet50,
+        Architecture::Densenet121,
+        Architecture::Vgg13,
+        Architecture::Vgg16,
+        Architecture::Vgg19,
+        Architecture::Squeezenet1_0,
+        Architecture::Squeezenet1_1,
+        Architecture::Alexnet,
+        Architecture::MobilenetV2,
+        Architecture::InceptionV3,
+    ];
+
+    /// Name used in the manifest, e.g. `mobilenet_v2`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Architecture::Resnet18 => "resnet18",
+            Architecture::Resnet34 => "resnet34",
+            Architecture::Resnet50 => "resnet50",
+            Architecture::Densenet121 => "densenet121",
+            Architecture::Vgg13 => "vgg13",
+            Architecture::Vgg16 => "vgg16",
+            Architecture::Vgg19 => "vgg19",
+            Architecture::Squeezenet1_0 => "squeezenet1_0",
+            Architecture::Squeezenet1_1 => "squeezenet1_1",
+            Architecture::Alexnet => "alexnet",
+            Architecture::MobilenetV2 => "mobilenet_v2",
+            Architecture::InceptionV3 => "inception_v3",
+        }
+    }
+
+    /// Guesses the architecture from a model name such as `resnet34` or `inception-v3`, which is
+    /// how the pre-trained weight files from `sync-models.sh` are named.
+    pub fn from_model_name(name: &str) -> Option<Architecture> {
+        let name = name.to_lowercase().replace('-', "_");
+        Architecture::ALL.iter().copied().find(|a| a.name() == name)
+    }
+
+    /// Builds the network for this architecture under `p`.
+    #[cfg(feature = "torch")]
+    pub fn build(&self, p: &Path, num_classes: i64) -> Box<dyn ModuleT + Send> {
+        match self {
+            Architecture::Resnet18 => Box::new(vision::resnet::resnet18(p, num_classes)),
+            Architecture::Resnet34 => Box::new(vision::resnet::resnet34(p, num_classes)),
+            Architecture::Resnet50 => Box::new(vision::resnet::resnet50(p, num_classes)),
+            Architecture::Densenet121 => Box::new(vision::densenet::densenet121(p, num_classes)),
+            Architecture::Vgg13 => Box::new(vision::vgg::vgg13(p, num_classes)),
+            Architecture::Vgg16 => Box::new(vision::vgg::vgg16(p, num_classes)),
+            Architecture::Vgg19 => Box::new(vision::vgg::vgg19(p, num_classes)),
+            Architecture::Squeezenet1_0 => Box::new(vision::squeezenet::v1_0(p, num_classes)),
+            Architecture::Squeezenet1_1 => Box::new(vision::squeezenet::v1_1(p, num_classes)),
+            Architecture::Alexnet => Box::new(vision::alexnet::alexnet(p, num_classes)),
+            Architecture::MobilenetV2 => Box::new(vision::mobilenet::v2(p, num_classes)),
+            Architecture::InceptionV3 => Box::new(vision::inception::v3(p, num_classes)),
+        }
+    }
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Differences between the variables an architecture expects and the ones in a weight file.
+#[derive(Debug, Default, PartialEq)]
+pub struct WeightMismatch {
+    pub path: String,
+    pub architecture: String,
+    pub missing: Vec<String>,
+    pub unexpected: Vec<String>,
+    /// `(name, expected shape, shape in the weight file)`
+    pub mismatched: Vec<(String, Vec<i64>, Vec<i64>)>,
+}
+
+impl WeightMismatch {
+    /// Compares expected `(name, shape)` pairs against the ones found in the weight file.
+    pub fn compare(
+        path: &str,
+        architecture: &str,
+        expected: &HashMap<String, Vec<i64>>,
+        found: &HashMap<String, Vec<i64>>,
+    ) -> WeightMismatch {
+        let mut mismatch = WeightMismatch {
+            path: path.to_string(),
+            architecture: architecture.to_string(),
+            ..Default::default()
+        };
+        for (name, shape) in expected {
+            match found.get(name) {
+                None => mismatch.missing.push(name.clone()),
+                Some(other) if other != shape => {
+                    mismatch
+                        .mismatched
+                        .push((name.clone(), shape.clone(), other.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        for name in found.keys() {
+            if !expected.contains_key(name) {
+                mismatch.unexpected.push(name.clone());
+            }
+        }
+        mismatch.missing.sort();
+        mismatch.unexpected.sort();
+        mismatch.mismatched.sort();
+        mismatch
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+fn write_names(f: &mut fmt::Formatter<'_>, label: &str, names: &[String]) -> fmt::Result {
+    const SHOWN: usize = 10;
+    if names.is_empty() {
+        return Ok(());
+    }
+    write!(
+        f,
+        "; {} {}: {}",
+        names.len(),
+        label,
+        names[..names.len().min(SHOWN)].join(", ")
+    )?;
+    if names.len() > SHOWN {
+        write!(f, " and {} more", names.len() - SHOWN)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for WeightMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "weights {} do not fit architecture {}",
+            self.path, self.architecture
+        )?;
+        write_names(f, "missing variable(s)", &self.missing)?;
+        write_names(f, "unexpected variable(s)", &self.unexpected)?;
+        let mismatched: Vec<String> = self
+            .mismatched
+            .iter()
+            .map(|(name, expected, found)| format!("{} {:?} != {:?}", name, expected, found))
+            .collect();
+        write_names(f, "variable(s) with the wrong shape", &mismatched)
+    }
+}
+
+impl std::error::Error for WeightMismatch {}
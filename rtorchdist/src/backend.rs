@@ -0,0 +1,214 @@
+/*
+Inference backends: how a loaded model turns a batch of images into logits.
+ */
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "torch")]
+use std::collections::HashMap;
+#[cfg(feature = "torch")]
+use std::sync::Mutex;
+#[cfg(feature = "torch")]
+use tch::nn::ModuleT;
+#[cfg(feature = "torch")]
+use tch::{CModule, IValue};
+
+#[cfg(feature = "torch")]
+use crate::architecture::{Architecture, WeightMismatch};
+use crate::tensor::Tensor;
+
+/// File format of a model, set with `"format"` in the manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelFormat {
+    /// Named tensors saved from a `tch::nn::VarStore`, loaded into a Rust architecture.
+    #[default]
+    VarStore,
+    /// A scripted or traced TorchScript module, e.g. exported by `convertdl.py`.
+    #[serde(alias = "torchscript")]
+    TorchScript,
+    /// An ONNX graph run by the pure-Rust tract runtime, needs the `onnx` cargo feature.
+    Onnx,
+}
+
+impl ModelFormat {
+    /// Format implied by a weight file extension when scanning the model directory.
+    pub fn from_extension(extension: &str) -> Option<ModelFormat> {
+        match extension {
+            "ot" => Some(ModelFormat::VarStore),
+            "pt" | "ts" => Some(ModelFormat::TorchScript),
+            "onnx" => Some(ModelFormat::Onnx),
+            _ => None,
+        }
+    }
+
+    /// Whether this build can load the format, `varstore` and `torchscript` need the `torch`
+    /// cargo feature and `onnx` the `onnx` one.
+    pub fn is_available(&self) -> bool {
+        match self {
+            ModelFormat::VarStore | ModelFormat::TorchScript => cfg!(feature = "torch"),
+            ModelFormat::Onnx => cfg!(feature = "onnx"),
+        }
+    }
+
+    /// The cargo feature that builds in support for the format.
+    pub fn feature(&self) -> &'static str {
+        match self {
+            ModelFormat::VarStore | ModelFormat::TorchScript => "torch",
+            ModelFormat::Onnx => "onnx",
+        }
+    }
+}
+
+pub trait ModelBackend: Send + Sync {
+    /// Runs a batch of preprocessed images `[N, C, H, W]` through the model and returns the
+    /// logits `[N, classes]`.
+    fn forward(&self, images: &Tensor) -> Result<Tensor, Box<dyn std::error::Error>>;
+
+    fn format(&self) -> ModelFormat;
+}
+
+/// A `tch::vision` network with weights loaded into its `VarStore`.
+#[cfg(feature = "torch")]
+pub struct VarStoreBackend {
+    net: Mutex<Box<dyn ModuleT + Send>>,
+    // The network only holds views into the var-store variables, keep the
+    // store alive for as long as the backend is.
+    _vs: tch::nn::VarStore,
+}
+
+#[cfg(feature = "torch")]
+impl VarStoreBackend {
+    /// Builds `architecture` and loads `weight_file` into it. Fails with a [`WeightMismatch`]
+    /// report when the variables in the file don't fit the architecture.
+    pub fn load(
+        weight_file: &str,
+        architecture: Architecture,
+        num_classes: i64,
+    ) -> Result<VarStoreBackend, Box<dyn std::error::Error>> {
+        let vs = tch::nn::VarStore::new(tch::Device::Cpu);
+        let net = architecture.build(&vs.root(), num_classes);
+        let named_tensors: HashMap<String, Tensor> =
+            Tensor::load_multi(weight_file)?.into_iter().collect();
+
+        let variables = vs.variables();
+        let expected = variables
+            .iter()
+            .map(|(name, t)| (name.clone(), t.size()))
+            .collect();
+        let found = named_tensors
+            .iter()
+            .map(|(name, t)| (name.clone(), t.size()))
+            .collect();
+        let mismatch = WeightMismatch::compare(weight_file, architecture.name(), &expected, &found);
+        if !mismatch.is_empty() {
+            log::error!("func: VarStoreBackend::load: {}", mismatch);
+            return Err(mismatch.into());
+        }
+        tch::no_grad(|| -> Result<(), tch::TchError> {
+            for (name, mut var) in variables {
+                var.f_copy_(&named_tensors[&name])?;
+            }
+            Ok(())
+        })?;
+        Ok(VarStoreBackend {
+            net: Mutex::new(net),
+            _vs: vs,
+        })
+    }
+}
+
+#[cfg(feature = "torch")]
+impl ModelBackend for VarStoreBackend {
+    fn forward(&self, images: &Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        let net = self.net.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(tch::no_grad(|| {
+            net.forward_t(images, /*train=*/ false)
+        }))
+    }
+
+    fn format(&self) -> ModelFormat {
+        ModelFormat::VarStore
+    }
+}
+
+/// A TorchScript module, served without any Rust layer definitions.
+#[cfg(feature = "torch")]
+pub struct TorchScriptBackend {
+    module: CModule,
+}
+
+#[cfg(feature = "torch")]
+impl TorchScriptBackend {
+    pub fn load(path: &str) -> Result<TorchScriptBackend, Box<dyn std::error::Error>> {
+        let mut module = CModule::load_on_device(path, tch::Device::Cpu)?;
+        module.f_set_eval()?;
+        Ok(TorchScriptBackend { module })
+    }
+}
+
+#[cfg(feature = "torch")]
+impl ModelBackend for TorchScriptBackend {
+    fn forward(&self, images: &Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        let output = tch::no_grad(|| {
+            self.module
+                .forward_is(&[IValue::Tensor(images.shallow_clone())])
+        })?;
+        match output {
+            IValue::Tensor(logits) => Ok(logits),
+            // Models such as inception return `(logits, aux_logits)`.
+            IValue::Tuple(values) | IValue::GenericList(values) => {
+                match values.into_iter().next() {
+                    Some(IValue::Tensor(logits)) => Ok(logits),
+                    _ => Err("TorchScript model did not return a tensor".into()),
+                }
+            }
+            _ => Err("TorchScript model did not return a tensor".into()),
+        }
+    }
+
+    fn format(&self) -> ModelFormat {
+        ModelFormat::TorchScript
+    }
+}
+
+/// An ONNX graph run by tract. Images go through the same preprocessing as the tch backends and
+/// are copied in and out of tract tensors around the forward pass.
+#[cfg(feature = "onnx")]
+pub struct OnnxBackend {
+    plan: tract_onnx::prelude::TypedRunnableModel<tract_onnx::prelude::TypedModel>,
+}
+
+#[cfg(feature = "onnx")]
+impl OnnxBackend {
+    pub fn load(path: &str) -> Result<OnnxBackend, Box<dyn std::error::Error>> {
+        use tract_onnx::prelude::*;
+
+        let plan = tract_onnx::onnx()
+            .model_for_path(path)?
+            .into_optimized()?
+            .into_runnable()?;
+        Ok(OnnxBackend { plan })
+    }
+}
+
+#[cfg(feature = "onnx")]
+impl ModelBackend for OnnxBackend {
+    fn forward(&self, images: &Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        use tract_onnx::prelude::{tract_ndarray, tvec, IntoTensor};
+
+        let shape: Vec<usize> = images.size().iter().map(|d| *d as usize).collect();
+        let data = Vec::<f32>::from(images);
+        let input = tract_ndarray::ArrayD::from_shape_vec(shape, data)?.into_tensor();
+        let outputs = self.plan.run(tvec!(input.into()))?;
+        let logits = outputs
+            .first()
+            .ok_or("ONNX model did not return an output")?
+            .to_array_view::<f32>()?;
+        let size: Vec<i64> = logits.shape().iter().map(|d| *d as i64).collect();
+        let data: Vec<f32> = logits.iter().copied().collect();
+        Ok(Tensor::of_slice(&data).view(size.as_slice()))
+    }
+
+    fn format(&self) -> ModelFormat {
+        ModelFormat::Onnx
+    }
+}
@@ -0,0 +1,289 @@
+/*
+Dynamic micro-batching: queues preprocessed images for a model and runs them
+through one forward pass once enough requests arrived or the oldest one has
+waited long enough.
+ */
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::backend::ModelBackend;
+use crate::metrics::metrics;
+use crate::tensor::{Kind, Tensor};
+
+/// Largest `max_batch_size` allowed, also the top bucket of `rtorchdist_batch_size`.
+pub const BATCH_SIZE_LIMIT: usize = 256;
+
+fn default_max_batch_size() -> usize {
+    8
+}
+
+fn default_max_wait_ms() -> u64 {
+    5
+}
+
+/// Batching settings, `[batching]` in the config for every model and `"batch"` in the manifest
+/// for one. The defaults are 8 images and 5 ms, overridden by `MAX_BATCH_SIZE` and
+/// `MAX_BATCH_WAIT_MS`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BatchConfig {
+    /// Largest number of images sent through one forward pass, `1` disables batching.
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+    /// How long the first queued image waits for others before the batch is run.
+    #[serde(default = "default_max_wait_ms")]
+    pub max_wait_ms: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> BatchConfig {
+        BatchConfig {
+            max_batch_size: default_max_batch_size(),
+            max_wait_ms: default_max_wait_ms(),
+        }
+    }
+}
+
+/// `"batch"` of a model in the manifest, settings left out come from `[batching]`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_batch_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_wait_ms: Option<u64>,
+}
+
+impl BatchOverrides {
+    pub fn apply(&self, batching: &BatchConfig) -> BatchConfig {
+        BatchConfig {
+            max_batch_size: self.max_batch_size.unwrap_or(batching.max_batch_size),
+            max_wait_ms: self.max_wait_ms.unwrap_or(batching.max_wait_ms),
+        }
+    }
+}
+
+/// Number of forward passes run per batch size.
+#[derive(Default)]
+pub struct BatchStats {
+    batch_sizes: Mutex<BTreeMap<usize, u64>>,
+    images: AtomicU64,
+}
+
+impl BatchStats {
+    fn record(&self, batch_size: usize) {
+        let mut sizes = self.batch_sizes.lock().unwrap_or_else(|e| e.into_inner());
+        *sizes.entry(batch_size).or_insert(0) += 1;
+        self.images.fetch_add(batch_size as u64, Ordering::Relaxed);
+    }
+
+    /// `batch size -> number of batches` of that size.
+    pub fn batch_sizes(&self) -> BTreeMap<usize, u64> {
+        self.batch_sizes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn images(&self) -> u64 {
+        self.images.load(Ordering::Relaxed)
+    }
+}
+
+/// Batching settings and batch-size distribution reported by `GET /models`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchInfo {
+    pub max_batch_size: usize,
+    pub max_wait_ms: u64,
+    pub images: u64,
+    pub batch_sizes: BTreeMap<usize, u64>,
+}
+
+type Reply = oneshot::Sender<Result<Tensor, String>>;
+
+struct Job {
+    image: Tensor,
+    reply: Reply,
+}
+
+/// Queue in front of a model backend, drained by a dedicated worker thread.
+pub struct Batcher {
+    config: BatchConfig,
+    sender: Mutex<Option<mpsc::Sender<Job>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    /// Set by the worker thread once it ran every queued job and exits.
+    done: Arc<(Mutex<bool>, Condvar)>,
+    stats: Arc<BatchStats>,
+}
+
+impl Batcher {
+    pub fn start(name: &str, backend: Arc<dyn ModelBackend>, config: BatchConfig) -> Batcher {
+        if config.max_batch_size > BATCH_SIZE_LIMIT {
+            log::warn!(
+                "func: Batcher::start: model {}: max_batch_size {} lowered to {}",
+                name,
+                config.max_batch_size,
+                BATCH_SIZE_LIMIT
+            );
+        }
+        let config = BatchConfig {
+            max_batch_size: config.max_batch_size.clamp(1, BATCH_SIZE_LIMIT),
+            ..config
+        };
+        let (sender, receiver) = mpsc::channel();
+        let stats = Arc::new(BatchStats::default());
+        let done = Arc::new((Mutex::new(false), Condvar::new()));
+        let worker = std::thread::Builder::new()
+            .name(format!("batcher-{}", name))
+            .spawn({
+                let stats = stats.clone();
+                let done = done.clone();
+                let name = name.to_string();
+                move || {
+                    run(&name, receiver, backend, config, stats);
+                    let (finished, signal) = &*done;
+                    *finished.lock().unwrap_or_else(|e| e.into_inner()) = true;
+                    signal.notify_all();
+                }
+            })
+            .expect("failed to spawn batcher thread");
+        Batcher {
+            config,
+            sender: Mutex::new(Some(sender)),
+            worker: Mutex::new(Some(worker)),
+            done,
+            stats,
+        }
+    }
+
+    /// Queues a preprocessed `[C, H, W]` image and waits for its row of the batch output.
+    pub async fn submit(&self, image: Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .ok_or("batcher is shut down")?
+            .send(Job { image, reply })
+            .map_err(|_| "batcher is shut down")?;
+        let output = response.await.map_err(|_| "batcher dropped the request")?;
+        Ok(output?)
+    }
+
+    pub fn info(&self) -> BatchInfo {
+        BatchInfo {
+            max_batch_size: self.config.max_batch_size,
+            max_wait_ms: self.config.max_wait_ms,
+            images: self.stats.images(),
+            batch_sizes: self.stats.batch_sizes(),
+        }
+    }
+
+    /// Stops accepting images and waits until the queued ones ran or `deadline` passed.
+    /// Returns whether the queue was drained; otherwise the worker is left to finish on its own.
+    pub fn shutdown(&self, deadline: Instant) -> bool {
+        // Closing the queue lets the worker finish the queued jobs and exit.
+        self.sender.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let (finished, signal) = &*self.done;
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let finished = *signal
+            .wait_timeout_while(
+                finished.lock().unwrap_or_else(|e| e.into_inner()),
+                timeout,
+                |finished| !*finished,
+            )
+            .unwrap_or_else(|e| e.into_inner())
+            .0;
+        if finished {
+            let worker = self.worker.lock().unwrap_or_else(|e| e.into_inner()).take();
+            if let Some(handle) = worker {
+                let _ = handle.join();
+            }
+        }
+        finished
+    }
+}
+
+impl Drop for Batcher {
+    fn drop(&mut self) {
+        self.sender
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(worker) = self
+            .worker
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+        {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run(
+    name: &str,
+    receiver: mpsc::Receiver<Job>,
+    backend: Arc<dyn ModelBackend>,
+    config: BatchConfig,
+    stats: Arc<BatchStats>,
+) {
+    let max_wait = Duration::from_millis(config.max_wait_ms);
+    while let Ok(first) = receiver.recv() {
+        let deadline = Instant::now() + max_wait;
+        let mut jobs = vec![first];
+        while jobs.len() < config.max_batch_size {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match receiver.recv_timeout(deadline - now) {
+                Ok(job) => jobs.push(job),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        // Images of different sizes can't be stacked, run each size as its own batch.
+        while !jobs.is_empty() {
+            let size = jobs[0].image.size();
+            let (batch, rest): (Vec<Job>, Vec<Job>) =
+                jobs.into_iter().partition(|job| job.image.size() == size);
+            jobs = rest;
+            stats.record(batch.len());
+            metrics()
+                .batch_sizes
+                .with_label_values(&[name])
+                .observe(batch.len() as f64);
+            run_batch(backend.as_ref(), batch);
+        }
+    }
+    log::info!("func: batcher: queue closed, worker exiting");
+}
+
+fn run_batch(backend: &dyn ModelBackend, jobs: Vec<Job>) {
+    log::info!("func: run_batch: batch size: {}", jobs.len());
+    let (images, replies): (Vec<Tensor>, Vec<Reply>) =
+        jobs.into_iter().map(|job| (job.image, job.reply)).unzip();
+    let output = Tensor::f_stack(&images, 0)
+        .map_err(|e| e.to_string())
+        .and_then(|batch| backend.forward(&batch).map_err(|e| e.to_string()))
+        .and_then(|logits| logits.f_softmax(-1, Kind::Float).map_err(|e| e.to_string()));
+    match output {
+        Ok(output) => {
+            for (i, reply) in replies.into_iter().enumerate() {
+                // The caller may have gone away, e.g. the client disconnected.
+                let _ = reply.send(Ok(output.get(i as i64)));
+            }
+        }
+        Err(e) => {
+            log::error!("func: run_batch: forward pass failed: {}", e);
+            for reply in replies {
+                let _ = reply.send(Err(e.clone()));
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+/*
+Command line of the `rtorchdist` binary: `serve` runs the HTTP server, while
+`predict` and `check` run the same model code offline and print JSON.
+ */
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use std::fs;
+
+use crate::config::{Config, ConfigArgs};
+#[cfg(feature = "torch")]
+use crate::logic::tensor_device_cpu;
+use crate::logic::{files, predict_files, self_check_predict, PredictParams};
+use crate::registry::{Manifest, ModelRegistry};
+
+/// Image classification server for PyTorch models.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// `serve` when omitted.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Start the HTTP server.
+    Serve,
+    /// Classify image files and print one JSON result per file.
+    Predict {
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<String>,
+        /// Model to use, the default model when omitted.
+        #[arg(long, value_name = "NAME")]
+        model_name: Option<String>,
+        /// Number of classes per image.
+        #[arg(short, long)]
+        k: Option<usize>,
+        /// Lowest probability reported.
+        #[arg(long)]
+        min_confidence: Option<f64>,
+    },
+    /// Run the PyTorch and image prediction self checks, failing with exit code 1.
+    Check,
+}
+
+/// Loads every model named in `config`.
+pub fn load_registry(config: &Config) -> Result<ModelRegistry, Box<dyn std::error::Error>> {
+    let manifest = Manifest::discover(&config.models)?;
+    ModelRegistry::load(manifest, &config.batching, &config.health)
+}
+
+/// Loads only the model `name`, or the default model when `name` is `None`, for the commands
+/// that use a single model.
+pub fn load_one_model(
+    config: &Config,
+    name: Option<&str>,
+) -> Result<ModelRegistry, Box<dyn std::error::Error>> {
+    let mut manifest = Manifest::discover(&config.models)?;
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => manifest.default_name().ok_or("no models found to serve")?,
+    };
+    manifest.models.retain(|m| m.name == name);
+    if manifest.models.is_empty() {
+        return Err(format!("Model not found: {}", name).into());
+    }
+    manifest.default = Some(name);
+    ModelRegistry::load(manifest, &config.batching, &config.health)
+}
+
+fn print_json(value: &serde_json::Value) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+    );
+}
+
+fn print_error(code: &str, message: &str) {
+    print_json(&json!({ "status": "error", "code": code, "message": message }));
+}
+
+/// `rtorchdist predict`: prints the results in the shape of `/predict/batch`, with `field`
+/// and `filename` set to the path. Returns the exit code, 1 when any image failed.
+pub async fn predict(
+    config: &Config,
+    paths: &[String],
+    model_name: Option<&str>,
+    params: &PredictParams,
+) -> i32 {
+    let ranking = match params.resolve(&config.predict) {
+        Ok(ranking) => ranking,
+        Err(e) => {
+            print_error("invalid_params", &e);
+            return 2;
+        }
+    };
+    let mut uploaded = Vec::new();
+    for path in paths {
+        match fs::read(path) {
+            Ok(bytes) => uploaded.push(files::UploadedFile {
+                field: path.clone(),
+                filename: Some(path.clone()),
+                bytes,
+            }),
+            Err(e) => {
+                print_error("file_not_readable", &format!("{}: {}", path, e));
+                return 2;
+            }
+        }
+    }
+    let registry = match load_one_model(config, model_name) {
+        Ok(registry) => registry,
+        Err(e) => {
+            print_error("model_load_failed", &e.to_string());
+            return 1;
+        }
+    };
+    let items = predict_files(&registry.default_model(), uploaded, ranking, &config.limits).await;
+    let failed = items.iter().any(|item| item.status != "success");
+    print_json(&json!({
+        "status": if failed { "error" } else { "success" },
+        "result": items,
+    }));
+    i32::from(failed)
+}
+
+/// `rtorchdist check`: runs `tensor_device_cpu` and `self_check_predict` on the default model
+/// like the `/check_*` routes. Returns the exit code, 1 when a check failed. Builds without
+/// libtorch skip the tensor check.
+pub fn check(config: &Config) -> i32 {
+    #[cfg(feature = "torch")]
+    let tensor_size = Some(tensor_device_cpu());
+    #[cfg(not(feature = "torch"))]
+    let tensor_size: Option<String> = None;
+    log::info!("func: check: tensor_device_cpu: {:?}", tensor_size);
+    let registry = match load_one_model(config, None) {
+        Ok(registry) => registry,
+        Err(e) => {
+            print_error("model_load_failed", &e.to_string());
+            return 1;
+        }
+    };
+    match self_check_predict(&registry.default_model(), &config.models.self_check_image) {
+        Ok(prediction) => {
+            print_json(&json!({
+                "status": "success",
+                "result": {
+                    "tensor_device_cpu": tensor_size,
+                    "model": registry.default_name(),
+                    "self_check_predict": prediction,
+                },
+            }));
+            0
+        }
+        Err(e) => {
+            print_error(
+                "self_check_failed",
+                &format!("Image prediction self check failed with error: {}", e),
+            );
+            1
+        }
+    }
+}
@@ -0,0 +1,610 @@
+/*
+Server configuration: one typed `Config` read from a TOML file, then overridden
+by environment variables, then by command line flags, and validated before the
+server starts.
+ */
+use clap::Args;
+use image::ImageFormat;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+
+use crate::batch::{BatchConfig, BATCH_SIZE_LIMIT};
+use crate::fetch::FetchConfig;
+use crate::health::HealthConfig;
+use crate::logic::PredictConfig;
+use crate::registry::ModelsConfig;
+use crate::telemetry::{Exporter, TracingConfig};
+use crate::upload::UploadLimits;
+
+/// Config file read when neither `--config` nor `RTORCHDIST_CONFIG` name one.
+pub const DEFAULT_CONFIG_FILE: &str = "rtorchdist.toml";
+
+fn default_bind() -> String {
+    "0.0.0.0:8080".to_string()
+}
+
+fn default_workers() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+fn default_backlog() -> u32 {
+    1024
+}
+
+fn default_keep_alive_secs() -> u64 {
+    5
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_grpc_max_message_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_log_format() -> LogFormat {
+    LogFormat::Json
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// `[server]`: where and how the HTTP server listens.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `host:port` to listen on, `BIND_ADDRESS` (`0.0.0.0:8080`).
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    /// Number of HTTP worker threads, `WORKERS` (one per CPU).
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// Most connections waiting to be accepted, `BACKLOG` (1024).
+    #[serde(default = "default_backlog")]
+    pub backlog: u32,
+    /// How long idle connections are kept open, `KEEP_ALIVE_SECS` (5), `0` closes them after
+    /// each response.
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// On SIGTERM or SIGINT, how long requests in flight and queued inference may take to
+    /// finish before the server exits anyway, `SHUTDOWN_TIMEOUT_SECS` (30).
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// How long requests are still accepted after `/readyz` started failing on shutdown,
+    /// `SHUTDOWN_DELAY_SECS` (0).
+    #[serde(default)]
+    pub shutdown_delay_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: default_bind(),
+            workers: default_workers(),
+            backlog: default_backlog(),
+            keep_alive_secs: default_keep_alive_secs(),
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            shutdown_delay_secs: 0,
+        }
+    }
+}
+
+/// `[grpc]`: the v2 inference protocol over gRPC, served next to the HTTP server when the
+/// binary is built with the `grpc` feature.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// `host:port` to listen on, `GRPC_BIND`. No gRPC listener when unset.
+    #[serde(default)]
+    pub bind: Option<String>,
+    /// Largest request or response message in bytes, `GRPC_MAX_MESSAGE_BYTES` (64 MiB).
+    #[serde(default = "default_grpc_max_message_bytes")]
+    pub max_message_bytes: usize,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> GrpcConfig {
+        GrpcConfig {
+            bind: None,
+            max_message_bytes: default_grpc_max_message_bytes(),
+        }
+    }
+}
+
+/// How log lines are written.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// One JSON object per line, with the fields of the request and stage spans.
+    Json,
+    /// Human readable lines.
+    Text,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "json" => Ok(LogFormat::Json),
+            "text" => Ok(LogFormat::Text),
+            other => Err(format!("unknown log format {:?}, use json or text", other)),
+        }
+    }
+}
+
+/// `[logging]`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`, `LOG_LEVEL` (`info`).
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// `json` or `text`, `LOG_FORMAT` (`json`).
+    #[serde(default = "default_log_format")]
+    pub format: LogFormat,
+    /// File the log is appended to instead of stderr, `LOG_FILE`.
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            level: default_log_level(),
+            format: default_log_format(),
+            file: None,
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn level_filter(&self) -> Result<LevelFilter, String> {
+        LevelFilter::from_str(&self.level).map_err(|_| {
+            format!(
+                "unknown log level {:?}, use off, error, warn, info, debug or trace",
+                self.level
+            )
+        })
+    }
+}
+
+/// `[features]`: parts of the API that can be switched off, all on by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Features {
+    /// `application/json` bodies on the predict routes, `ENABLE_JSON_INPUT`.
+    #[serde(default = "default_enabled")]
+    pub json_input: bool,
+    /// Raw `image/*` bodies on the predict routes, `ENABLE_RAW_INPUT`.
+    #[serde(default = "default_enabled")]
+    pub raw_input: bool,
+    /// `{"url": ...}` JSON bodies, `ENABLE_URL_INPUT`. Also needs `fetch.allowed_hosts`.
+    #[serde(default = "default_enabled")]
+    pub url_input: bool,
+    /// The `/predict/batch` routes, `ENABLE_BATCH_ROUTES`.
+    #[serde(default = "default_enabled")]
+    pub batch_routes: bool,
+    /// The `/check_*` self check routes, `ENABLE_CHECK_ROUTES`.
+    #[serde(default = "default_enabled")]
+    pub check_routes: bool,
+    /// `GET /metrics`, `ENABLE_METRICS_ROUTE`.
+    #[serde(default = "default_enabled")]
+    pub metrics_route: bool,
+    /// The Open Inference Protocol (KServe v2) routes under `/v2`, `ENABLE_V2_ROUTES`.
+    #[serde(default = "default_enabled")]
+    pub v2_routes: bool,
+    /// The TensorFlow Serving compatible `/v1/models/{name}:predict` route,
+    /// `ENABLE_TF_SERVING_ROUTES`.
+    #[serde(default = "default_enabled")]
+    pub tf_serving_routes: bool,
+    /// `POST /models/{name}/reload`, `ENABLE_RELOAD_ROUTE`. Off by default.
+    #[serde(default)]
+    pub reload_route: bool,
+}
+
+impl Default for Features {
+    fn default() -> Features {
+        Features {
+            json_input: true,
+            raw_input: true,
+            url_input: true,
+            batch_routes: true,
+            check_routes: true,
+            metrics_route: true,
+            v2_routes: true,
+            tf_serving_routes: true,
+            reload_route: false,
+        }
+    }
+}
+
+/// Everything the server is configured with, see `rtorchdist.toml` in the README.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub grpc: GrpcConfig,
+    #[serde(default)]
+    pub models: ModelsConfig,
+    #[serde(default)]
+    pub limits: UploadLimits,
+    #[serde(default)]
+    pub fetch: FetchConfig,
+    #[serde(default)]
+    pub batching: BatchConfig,
+    #[serde(default)]
+    pub predict: PredictConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    #[serde(default)]
+    pub features: Features,
+}
+
+/// Command line flags overriding the config file and environment.
+#[derive(Args, Debug, Clone, Default)]
+pub struct ConfigArgs {
+    /// Config file, `RTORCHDIST_CONFIG` or `rtorchdist.toml` if it exists.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<String>,
+    /// Address to listen on, e.g. `127.0.0.1:8080`.
+    #[arg(long, global = true)]
+    pub bind: Option<String>,
+    /// Address to serve gRPC on, e.g. `127.0.0.1:8081`.
+    #[arg(long, global = true, value_name = "ADDRESS")]
+    pub grpc_bind: Option<String>,
+    /// Number of HTTP worker threads.
+    #[arg(long, global = true)]
+    pub workers: Option<usize>,
+    /// Directory scanned for model files.
+    #[arg(long, global = true, value_name = "DIR")]
+    pub model_dir: Option<String>,
+    /// Model manifest.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub manifest: Option<String>,
+    /// Serve this single weight file.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub model: Option<String>,
+    /// Largest accepted upload in bytes.
+    #[arg(long, global = true, value_name = "BYTES")]
+    pub max_upload_bytes: Option<usize>,
+    /// Log level.
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+}
+
+/// Every problem found while loading or validating the config, reported together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for error in &self.0 {
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().to_lowercase())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Environment variables read by `Config::apply_env`.
+struct EnvOverrides<'a> {
+    var: &'a dyn Fn(&str) -> Option<String>,
+    errors: Vec<String>,
+}
+
+impl EnvOverrides<'_> {
+    fn set<T: FromStr>(&mut self, name: &str, field: &mut T)
+    where
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = (self.var)(name) {
+            match value.trim().parse() {
+                Ok(value) => *field = value,
+                Err(e) => self.errors.push(format!("{}={:?}: {}", name, value, e)),
+            }
+        }
+    }
+
+    fn set_string(&mut self, name: &str, field: &mut String) {
+        if let Some(value) = (self.var)(name) {
+            *field = value;
+        }
+    }
+
+    fn set_option(&mut self, name: &str, field: &mut Option<String>) {
+        if let Some(value) = (self.var)(name) {
+            *field = Some(value);
+        }
+    }
+
+    fn set_list(&mut self, name: &str, field: &mut Vec<String>) {
+        if let Some(value) = (self.var)(name) {
+            *field = list(&value);
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file named by `args`, `RTORCHDIST_CONFIG` or `rtorchdist.toml`, applies
+    /// the environment and `args` on top and validates the result.
+    pub fn load(args: &ConfigArgs) -> Result<Config, ConfigError> {
+        let path = args
+            .config
+            .clone()
+            .or_else(|| env::var("RTORCHDIST_CONFIG").ok());
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(DEFAULT_CONFIG_FILE)?
+            }
+            None => Config::default(),
+        };
+        config.apply_env(&|name| env::var(name).ok())?;
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError(vec![format!("cannot read {}: {}", path, e)]))?;
+        Config::from_toml(&contents).map_err(|ConfigError(errors)| {
+            ConfigError(errors.iter().map(|e| format!("{}: {}", path, e)).collect())
+        })
+    }
+
+    /// Parses a TOML config, settings missing from it keep their defaults.
+    pub fn from_toml(contents: &str) -> Result<Config, ConfigError> {
+        toml::from_str(contents).map_err(|e| ConfigError(vec![e.message().to_string()]))
+    }
+
+    /// Overrides settings with the environment variables `var` returns, e.g.
+    /// `&|name| std::env::var(name).ok()`.
+    pub fn apply_env(&mut self, var: &dyn Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let mut env = EnvOverrides {
+            var,
+            errors: Vec::new(),
+        };
+        env.set_string("BIND_ADDRESS", &mut self.server.bind);
+        env.set("WORKERS", &mut self.server.workers);
+        env.set("BACKLOG", &mut self.server.backlog);
+        env.set("KEEP_ALIVE_SECS", &mut self.server.keep_alive_secs);
+        env.set(
+            "SHUTDOWN_TIMEOUT_SECS",
+            &mut self.server.shutdown_timeout_secs,
+        );
+        env.set("SHUTDOWN_DELAY_SECS", &mut self.server.shutdown_delay_secs);
+
+        env.set_option("GRPC_BIND", &mut self.grpc.bind);
+        env.set("GRPC_MAX_MESSAGE_BYTES", &mut self.grpc.max_message_bytes);
+
+        env.set_string("MODEL_DIR", &mut self.models.dir);
+        env.set_option("MODEL_MANIFEST", &mut self.models.manifest);
+        env.set_option("MODEL_PATH", &mut self.models.path);
+        env.set_string("IMAGE_PATH", &mut self.models.self_check_image);
+
+        env.set("MAX_UPLOAD_BYTES", &mut self.limits.max_bytes);
+        env.set("MAX_IMAGE_WIDTH", &mut self.limits.max_width);
+        env.set("MAX_IMAGE_HEIGHT", &mut self.limits.max_height);
+        env.set("MAX_IMAGE_PIXELS", &mut self.limits.max_pixels);
+        env.set("MAX_IMAGE_FRAMES", &mut self.limits.max_frames);
+        env.set_list("ALLOWED_IMAGE_FORMATS", &mut self.limits.allowed_formats);
+
+        env.set_list("FETCH_ALLOWED_HOSTS", &mut self.fetch.allowed_hosts);
+        env.set("FETCH_ALLOW_PRIVATE", &mut self.fetch.allow_private);
+        env.set(
+            "FETCH_CONNECT_TIMEOUT_MS",
+            &mut self.fetch.connect_timeout_ms,
+        );
+        env.set("FETCH_TIMEOUT_MS", &mut self.fetch.timeout_ms);
+        env.set("FETCH_MAX_BYTES", &mut self.fetch.max_bytes);
+        env.set("FETCH_MAX_REDIRECTS", &mut self.fetch.max_redirects);
+
+        env.set("MAX_BATCH_SIZE", &mut self.batching.max_batch_size);
+        env.set("MAX_BATCH_WAIT_MS", &mut self.batching.max_wait_ms);
+
+        env.set("DEFAULT_TOP_K", &mut self.predict.default_top_k);
+        env.set("MAX_TOP_K", &mut self.predict.max_top_k);
+        env.set(
+            "DEFAULT_MIN_CONFIDENCE",
+            &mut self.predict.default_min_confidence,
+        );
+
+        env.set(
+            "MAX_INFERENCE_FAILURES",
+            &mut self.health.max_consecutive_failures,
+        );
+        env.set("STARTUP_SELF_CHECK", &mut self.health.startup_self_check);
+
+        env.set_string("LOG_LEVEL", &mut self.logging.level);
+        env.set("LOG_FORMAT", &mut self.logging.format);
+        env.set_option("LOG_FILE", &mut self.logging.file);
+
+        env.set("TRACE_EXPORTER", &mut self.tracing.exporter);
+        env.set_string("TRACE_FILE", &mut self.tracing.file);
+        env.set_string("OTLP_ENDPOINT", &mut self.tracing.otlp_endpoint);
+        env.set_string("OTEL_SERVICE_NAME", &mut self.tracing.service_name);
+
+        env.set("ENABLE_JSON_INPUT", &mut self.features.json_input);
+        env.set("ENABLE_RAW_INPUT", &mut self.features.raw_input);
+        env.set("ENABLE_URL_INPUT", &mut self.features.url_input);
+        env.set("ENABLE_BATCH_ROUTES", &mut self.features.batch_routes);
+        env.set("ENABLE_CHECK_ROUTES", &mut self.features.check_routes);
+        env.set("ENABLE_METRICS_ROUTE", &mut self.features.metrics_route);
+        env.set("ENABLE_V2_ROUTES", &mut self.features.v2_routes);
+        env.set(
+            "ENABLE_TF_SERVING_ROUTES",
+            &mut self.features.tf_serving_routes,
+        );
+        env.set("ENABLE_RELOAD_ROUTE", &mut self.features.reload_route);
+
+        if env.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(env.errors))
+        }
+    }
+
+    /// Overrides settings with the flags given on the command line.
+    pub fn apply_args(&mut self, args: &ConfigArgs) {
+        if let Some(bind) = &args.bind {
+            self.server.bind = bind.clone();
+        }
+        if let Some(bind) = &args.grpc_bind {
+            self.grpc.bind = Some(bind.clone());
+        }
+        if let Some(workers) = args.workers {
+            self.server.workers = workers;
+        }
+        if let Some(dir) = &args.model_dir {
+            self.models.dir = dir.clone();
+        }
+        if let Some(manifest) = &args.manifest {
+            self.models.manifest = Some(manifest.clone());
+        }
+        if let Some(path) = &args.model {
+            self.models.path = Some(path.clone());
+        }
+        if let Some(max_bytes) = args.max_upload_bytes {
+            self.limits.max_bytes = max_bytes;
+        }
+        if let Some(level) = &args.log_level {
+            self.logging.level = level.clone();
+        }
+    }
+
+    /// Checks every setting, reporting all problems at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        if self.server.bind.to_socket_addrs().is_err() {
+            errors.push(format!(
+                "server.bind: {:?} is not a host:port address",
+                self.server.bind
+            ));
+        }
+        if self.server.workers == 0 {
+            errors.push("server.workers: must be at least 1".to_string());
+        }
+        if let Some(bind) = &self.grpc.bind {
+            if !cfg!(feature = "grpc") {
+                errors.push("grpc.bind: rtorchdist was built without the grpc feature".to_string());
+            } else if bind.to_socket_addrs().is_err() {
+                errors.push(format!("grpc.bind: {:?} is not a host:port address", bind));
+            }
+        }
+
+        if let Some(path) = &self.models.path {
+            if !Path::new(path).is_file() {
+                errors.push(format!("models.path: no such file {:?}", path));
+            }
+        }
+        if let Some(manifest) = &self.models.manifest {
+            if !Path::new(manifest).is_file() {
+                errors.push(format!("models.manifest: no such file {:?}", manifest));
+            }
+        }
+
+        let limits = &self.limits;
+        for (name, value) in [
+            ("server.backlog", self.server.backlog as u64),
+            ("grpc.max_message_bytes", self.grpc.max_message_bytes as u64),
+            ("limits.max_bytes", limits.max_bytes as u64),
+            ("limits.max_width", limits.max_width as u64),
+            ("limits.max_height", limits.max_height as u64),
+            ("limits.max_pixels", limits.max_pixels),
+            ("limits.max_frames", limits.max_frames as u64),
+            ("fetch.connect_timeout_ms", self.fetch.connect_timeout_ms),
+            ("fetch.timeout_ms", self.fetch.timeout_ms),
+            ("fetch.max_bytes", self.fetch.max_bytes as u64),
+            (
+                "batching.max_batch_size",
+                self.batching.max_batch_size as u64,
+            ),
+            ("predict.default_top_k", self.predict.default_top_k as u64),
+            ("predict.max_top_k", self.predict.max_top_k as u64),
+            (
+                "health.max_consecutive_failures",
+                self.health.max_consecutive_failures as u64,
+            ),
+        ] {
+            if value == 0 {
+                errors.push(format!("{}: must be greater than 0", name));
+            }
+        }
+        if self.batching.max_batch_size > BATCH_SIZE_LIMIT {
+            errors.push(format!(
+                "batching.max_batch_size: must be at most {}, got {}",
+                BATCH_SIZE_LIMIT, self.batching.max_batch_size
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.predict.default_min_confidence) {
+            errors.push(format!(
+                "predict.default_min_confidence: must be between 0 and 1, got {}",
+                self.predict.default_min_confidence
+            ));
+        }
+        if limits.allowed_formats.is_empty() {
+            errors.push("limits.allowed_formats: must not be empty".to_string());
+        }
+        for format in &limits.allowed_formats {
+            if ImageFormat::from_extension(format).is_none() {
+                errors.push(format!(
+                    "limits.allowed_formats: unknown image format {:?}",
+                    format
+                ));
+            }
+        }
+
+        if let Err(e) = self.logging.level_filter() {
+            errors.push(format!("logging.level: {}", e));
+        }
+        match self.tracing.exporter {
+            Exporter::File if self.tracing.file.is_empty() => {
+                errors.push("tracing.file: must not be empty".to_string());
+            }
+            Exporter::Otlp if url::Url::parse(&self.tracing.otlp_endpoint).is_err() => {
+                errors.push(format!(
+                    "tracing.otlp_endpoint: {:?} is not a URL",
+                    self.tracing.otlp_endpoint
+                ));
+            }
+            _ => {}
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(errors))
+        }
+    }
+}
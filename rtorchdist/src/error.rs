@@ -0,0 +1,134 @@
+/*
+API errors: every failing route answers with the same JSON shape,
+`{"status": "error", "code": "<stable code>", "message": "<details>"}`.
+ */
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
+use std::fmt;
+
+use crate::fetch::FetchError;
+use crate::registry::ReloadError;
+use crate::upload::UploadError;
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// Query or body parameters that can't be used, e.g. `min_confidence=2`.
+    InvalidParams(String),
+    ModelNotFound(String),
+    /// A request body in a content type the route doesn't take.
+    UnsupportedContentType(String),
+    /// A request needing a part of the API that is switched off in the config.
+    FeatureDisabled(&'static str),
+    Upload(UploadError),
+    Fetch(FetchError),
+    Reload(ReloadError),
+    /// The model failed on an image that passed the upload checks.
+    Inference(String),
+    SelfCheck(String),
+}
+
+impl ApiError {
+    /// Stable, machine-readable error code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidParams(_) => "invalid_params",
+            ApiError::ModelNotFound(_) => "model_not_found",
+            ApiError::UnsupportedContentType(_) => "unsupported_content_type",
+            ApiError::FeatureDisabled(_) => "feature_disabled",
+            ApiError::Upload(e) => e.code(),
+            ApiError::Fetch(e) => e.code(),
+            ApiError::Reload(ReloadError::NotFound(_)) => "model_not_found",
+            ApiError::Reload(ReloadError::InProgress(_)) => "reload_in_progress",
+            ApiError::Reload(ReloadError::Failed(_)) => "reload_failed",
+            ApiError::Inference(_) => "inference_failed",
+            ApiError::SelfCheck(_) => "self_check_failed",
+        }
+    }
+
+    /// Sorts an error from the prediction functions into a client error for refused uploads
+    /// and a server error for everything else.
+    pub fn from_prediction(error: Box<dyn std::error::Error>) -> ApiError {
+        match error.downcast::<UploadError>() {
+            Ok(e) => ApiError::Upload(*e),
+            Err(e) => ApiError::Inference(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::InvalidParams(e) => write!(f, "{}", e),
+            ApiError::ModelNotFound(name) => write!(f, "Model not found: {}", name),
+            ApiError::UnsupportedContentType(content_type) => {
+                write!(f, "Unsupported content type: {}", content_type)
+            }
+            ApiError::FeatureDisabled(feature) => {
+                write!(f, "The {} feature is disabled on this server", feature)
+            }
+            ApiError::Upload(e) => write!(f, "{}", e),
+            ApiError::Fetch(e) => write!(f, "{}", e),
+            ApiError::Reload(e) => write!(f, "{}", e),
+            ApiError::Inference(e) => write!(f, "Prediction failed with error: {}", e),
+            ApiError::SelfCheck(e) => {
+                write!(f, "Image prediction self check failed with error: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<UploadError> for ApiError {
+    fn from(error: UploadError) -> ApiError {
+        ApiError::Upload(error)
+    }
+}
+
+impl From<FetchError> for ApiError {
+    fn from(error: FetchError) -> ApiError {
+        ApiError::Fetch(error)
+    }
+}
+
+impl From<ReloadError> for ApiError {
+    fn from(error: ReloadError) -> ApiError {
+        ApiError::Reload(error)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidParams(_) => StatusCode::BAD_REQUEST,
+            ApiError::ModelNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::UnsupportedContentType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::FeatureDisabled(_) => StatusCode::FORBIDDEN,
+            ApiError::Upload(e) => e.status_code(),
+            ApiError::Fetch(e) => match e {
+                FetchError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+                FetchError::HostNotAllowed(_) | FetchError::PrivateAddress(_) => {
+                    StatusCode::FORBIDDEN
+                }
+                FetchError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                FetchError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                FetchError::TooManyRedirects(_) | FetchError::Failed(_) => StatusCode::BAD_GATEWAY,
+            },
+            ApiError::Reload(e) => match e {
+                ReloadError::NotFound(_) => StatusCode::NOT_FOUND,
+                ReloadError::InProgress(_) => StatusCode::CONFLICT,
+                ReloadError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            ApiError::Inference(_) | ApiError::SelfCheck(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({
+            "status": "error",
+            "code": self.code(),
+            "message": self.to_string(),
+        }))
+    }
+}
@@ -0,0 +1,295 @@
+/*
+Fetches images from URLs for `{"url": ...}` predict requests, limited to
+allowlisted hosts and public addresses.
+ */
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use url::Url;
+
+fn default_connect_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_max_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_max_redirects() -> u32 {
+    3
+}
+
+/// URL fetch settings, `[fetch]` in the config. By default no host is allowed, so fetching is
+/// off; the timeouts are 2000 and 10000 ms, downloads are limited to 10 MiB and 3 redirects.
+/// The `FETCH_*` environment variables override them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FetchConfig {
+    /// Host names that may be fetched: exact names, `*.example.com` for subdomains, or `*`.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    /// Allow loopback, private, link-local and other non-public addresses.
+    #[serde(default)]
+    pub allow_private: bool,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    /// Longest time for the whole download, redirects included, so a server sending the
+    /// response slowly can't hold a worker. Also bounds every single read.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    #[serde(default = "default_max_redirects")]
+    pub max_redirects: u32,
+}
+
+impl Default for FetchConfig {
+    fn default() -> FetchConfig {
+        FetchConfig {
+            allowed_hosts: Vec::new(),
+            allow_private: false,
+            connect_timeout_ms: default_connect_timeout_ms(),
+            timeout_ms: default_timeout_ms(),
+            max_bytes: default_max_bytes(),
+            max_redirects: default_max_redirects(),
+        }
+    }
+}
+
+impl FetchConfig {
+    pub fn host_allowed(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        self.allowed_hosts.iter().any(|allowed| {
+            if allowed == "*" {
+                return true;
+            }
+            match allowed.strip_prefix("*.") {
+                Some(domain) => host.ends_with(&format!(".{}", domain)),
+                None => *allowed == host,
+            }
+        })
+    }
+}
+
+/// Why a URL could not be fetched. Each variant has a stable `code` returned to the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchError {
+    InvalidUrl(String),
+    HostNotAllowed(String),
+    PrivateAddress(String),
+    TooManyRedirects(u32),
+    TooLarge {
+        limit: usize,
+    },
+    Timeout,
+    /// The server answered with an error status or the connection failed.
+    Failed(String),
+}
+
+impl FetchError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            FetchError::InvalidUrl(_) => "invalid_url",
+            FetchError::HostNotAllowed(_) => "url_host_not_allowed",
+            FetchError::PrivateAddress(_) => "url_private_address",
+            FetchError::TooManyRedirects(_) => "url_too_many_redirects",
+            FetchError::TooLarge { .. } => "payload_too_large",
+            FetchError::Timeout => "url_fetch_timeout",
+            FetchError::Failed(_) => "url_fetch_failed",
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::InvalidUrl(e) => write!(f, "Invalid URL: {}", e),
+            FetchError::HostNotAllowed(host) => write!(f, "Host {} is not allowed", host),
+            FetchError::PrivateAddress(host) => {
+                write!(f, "Host {} resolves to a private address", host)
+            }
+            FetchError::TooManyRedirects(limit) => {
+                write!(f, "More than {} redirects", limit)
+            }
+            FetchError::TooLarge { limit } => {
+                write!(f, "Download exceeds the limit of {} bytes", limit)
+            }
+            FetchError::Timeout => write!(f, "Timed out fetching the URL"),
+            FetchError::Failed(e) => write!(f, "Fetching the URL failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+/// Loopback, private, link-local, shared, benchmarking, reserved, multicast, NAT64 and other
+/// addresses that aren't reachable on the public internet.
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // 100.64.0.0/10 carrier-grade NAT.
+                || (a == 100 && (64..128).contains(&b))
+                // 198.18.0.0/15 benchmarking.
+                || (a == 198 && (b & 0xfe) == 18)
+                // 240.0.0.0/4 reserved.
+                || a >= 240
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_private(IpAddr::V4(v4));
+            }
+            let segments = ip.segments();
+            let first = segments[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // fc00::/7 unique local and fe80::/10 link-local.
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // 64:ff9b::/96 NAT64, which reaches any IPv4 address through the translator.
+                || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+        }
+    }
+}
+
+/// Resolves hosts for the HTTP client and drops the connection when any address is private, so
+/// a name can't be pointed at internal services between the check and the connect.
+struct PublicResolver {
+    allow_private: bool,
+}
+
+impl ureq::Resolver for PublicResolver {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+        if !self.allow_private && addrs.iter().any(|a| is_private(a.ip())) {
+            let host = netloc.rsplit_once(':').map(|(h, _)| h).unwrap_or(netloc);
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                FetchError::PrivateAddress(host.to_string()),
+            ));
+        }
+        Ok(addrs)
+    }
+}
+
+/// Checks the scheme and host of a URL against `config`.
+pub fn check_url(url: &Url, config: &FetchConfig) -> Result<(), FetchError> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(FetchError::InvalidUrl(format!(
+            "unsupported scheme {}",
+            url.scheme()
+        )));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| FetchError::InvalidUrl("URL has no host".to_string()))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if !config.host_allowed(host) {
+        return Err(FetchError::HostNotAllowed(host.to_string()));
+    }
+    Ok(())
+}
+
+/// Maps a client error to a `FetchError`, finding the resolver's refusal or a timeout among
+/// its causes.
+fn fetch_error(error: &(dyn std::error::Error + 'static)) -> FetchError {
+    let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(e) = cause {
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            if let Some(fetch) = e.get_ref().and_then(|e| e.downcast_ref::<FetchError>()) {
+                return fetch.clone();
+            }
+            if matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ) {
+                return FetchError::Timeout;
+            }
+        }
+        cause = e.source();
+    }
+    FetchError::Failed(error.to_string())
+}
+
+/// Downloads `url`, following up to `max_redirects` redirects, each of which must pass the same
+/// checks. Blocking, run it on a thread pool.
+pub fn fetch(url: &str, config: &FetchConfig) -> Result<Vec<u8>, FetchError> {
+    let deadline = Instant::now() + Duration::from_millis(config.timeout_ms);
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_millis(config.connect_timeout_ms))
+        // Redirects are followed below so that every hop is checked.
+        .redirects(0)
+        .resolver(PublicResolver {
+            allow_private: config.allow_private,
+        })
+        .build();
+    let mut url = Url::parse(url).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+    let mut redirects = 0;
+    let response = loop {
+        check_url(&url, config)?;
+        log::info!("func: fetch: fetching: {}", url);
+        // Every hop and the body share the deadline of the whole download.
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(FetchError::Timeout);
+        }
+        let response = match agent.request_url("GET", &url).timeout(remaining).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, _)) => {
+                return Err(FetchError::Failed(format!("server returned {}", status)))
+            }
+            Err(e) => return Err(fetch_error(&e)),
+        };
+        if !(300..400).contains(&response.status()) {
+            break response;
+        }
+        if redirects >= config.max_redirects {
+            return Err(FetchError::TooManyRedirects(config.max_redirects));
+        }
+        redirects += 1;
+        let location = response
+            .header("location")
+            .ok_or_else(|| FetchError::Failed("redirect without a location".to_string()))?;
+        url = url
+            .join(location)
+            .map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+    };
+    if let Some(length) = response
+        .header("content-length")
+        .and_then(|l| l.parse::<usize>().ok())
+    {
+        if length > config.max_bytes {
+            return Err(FetchError::TooLarge {
+                limit: config.max_bytes,
+            });
+        }
+    }
+    let mut bytes = Vec::new();
+    response
+        .into_reader()
+        .take(config.max_bytes as u64 + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| fetch_error(&e))?;
+    if bytes.len() > config.max_bytes {
+        return Err(FetchError::TooLarge {
+            limit: config.max_bytes,
+        });
+    }
+    log::info!("func: fetch: fetched {} bytes from {}", bytes.len(), url);
+    Ok(bytes)
+}
@@ -0,0 +1,402 @@
+/*
+gRPC listener for the v2 inference protocol (`inference.GRPCInferenceService`),
+serving the same models and batching queues as the HTTP routes. Built with the
+`grpc` cargo feature and started when `grpc.bind` is set.
+ */
+use actix_web::web;
+use actix_web::ResponseError;
+use futures::channel::oneshot;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Code, Request, Response, Status};
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::logic::Model;
+use crate::preprocess::PreprocessConfig;
+use crate::registry::ModelRegistry;
+use crate::shutdown::Listener;
+use crate::upload::UploadLimits;
+use crate::v2::{self, InputImages, InputShape, ModelMetadata, Output, OutputData, OutputKind};
+
+/// Messages and service generated from `proto/grpc_predict_v2.proto`.
+pub mod proto {
+    tonic::include_proto!("inference");
+}
+
+use proto::grpc_inference_service_server::{GrpcInferenceService, GrpcInferenceServiceServer};
+use proto::infer_parameter::ParameterChoice;
+use proto::model_infer_request::InferInputTensor;
+use proto::model_infer_response::InferOutputTensor;
+use proto::model_metadata_response::TensorMetadata;
+use proto::*;
+
+/// Splits raw `BYTES` tensor contents into its elements, each prefixed with its length as a
+/// 4-byte little-endian integer.
+pub fn decode_raw_bytes(mut raw: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut elements = Vec::new();
+    while !raw.is_empty() {
+        if raw.len() < 4 {
+            return Err("raw BYTES contents end inside a length prefix".to_string());
+        }
+        let (prefix, rest) = raw.split_at(4);
+        let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+        if rest.len() < len {
+            return Err(format!(
+                "raw BYTES element {} is {} bytes, only {} left",
+                elements.len(),
+                len,
+                rest.len()
+            ));
+        }
+        let (element, rest) = rest.split_at(len);
+        elements.push(element.to_vec());
+        raw = rest;
+    }
+    Ok(elements)
+}
+
+/// The inverse of [`decode_raw_bytes`].
+pub fn encode_raw_bytes<T: AsRef<[u8]>>(elements: &[T]) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for element in elements {
+        let element = element.as_ref();
+        raw.extend_from_slice(&(element.len() as u32).to_le_bytes());
+        raw.extend_from_slice(element);
+    }
+    raw
+}
+
+/// Reads the images of `input`, from `raw` when the request sent its contents as raw bytes.
+pub fn input_images(
+    input: &InferInputTensor,
+    raw: Option<&[u8]>,
+    preprocess: &PreprocessConfig,
+    limits: &UploadLimits,
+) -> Result<InputImages, String> {
+    let shape = v2::input_shape(
+        &input.name,
+        &input.datatype,
+        &input.shape,
+        preprocess,
+        limits,
+    )?;
+    let contents = input.contents.clone().unwrap_or_default();
+    match shape {
+        InputShape::Tensor(shape) => {
+            let data: Vec<f32> = match (raw, input.datatype.as_str()) {
+                (Some(raw), "FP32") if raw.len() % 4 == 0 => raw
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+                (Some(raw), "FP64") if raw.len() % 8 == 0 => raw
+                    .chunks_exact(8)
+                    .map(|b| {
+                        f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32
+                    })
+                    .collect(),
+                (Some(raw), datatype) => {
+                    return Err(format!(
+                        "{} bytes of raw contents don't hold {} values",
+                        raw.len(),
+                        datatype
+                    ))
+                }
+                (None, "FP32") => contents.fp32_contents,
+                (None, _) => contents.fp64_contents.iter().map(|v| *v as f32).collect(),
+            };
+            v2::check_len(&input.shape, data.len())?;
+            Ok(InputImages::Tensor { data, shape })
+        }
+        InputShape::Encoded(_) => {
+            let images = match raw {
+                Some(raw) => decode_raw_bytes(raw)?,
+                None => contents.bytes_contents,
+            };
+            v2::check_len(&input.shape, images.len())?;
+            Ok(InputImages::Encoded(images))
+        }
+    }
+}
+
+/// The outputs asked for in `request`, the probabilities when none are named.
+pub fn output_kinds(request: &ModelInferRequest) -> Result<Vec<OutputKind>, String> {
+    if request.outputs.is_empty() {
+        return Ok(vec![OutputKind::Probabilities]);
+    }
+    request
+        .outputs
+        .iter()
+        .map(|output| {
+            let classification = match output
+                .parameters
+                .get("classification")
+                .and_then(|p| p.parameter_choice.as_ref())
+            {
+                Some(ParameterChoice::Int64Param(k)) => Some(*k),
+                Some(_) => Some(0),
+                None => None,
+            };
+            v2::output_kind(&output.name, classification)
+        })
+        .collect()
+}
+
+/// An output tensor, with its values in the returned raw bytes when `raw` is set.
+pub fn output_tensor(output: Output, raw: bool) -> (InferOutputTensor, Option<Vec<u8>>) {
+    let mut tensor = InferOutputTensor {
+        name: v2::OUTPUT_NAME.to_string(),
+        datatype: output.data.datatype().to_string(),
+        shape: output.shape.iter().map(|dim| *dim as i64).collect(),
+        ..InferOutputTensor::default()
+    };
+    match (output.data, raw) {
+        (OutputData::Fp32(data), true) => {
+            let bytes = data.iter().flat_map(|v| v.to_le_bytes()).collect();
+            (tensor, Some(bytes))
+        }
+        (OutputData::Bytes(data), true) => (tensor, Some(encode_raw_bytes(&data))),
+        (OutputData::Fp32(data), false) => {
+            tensor.contents = Some(InferTensorContents {
+                fp32_contents: data,
+                ..InferTensorContents::default()
+            });
+            (tensor, None)
+        }
+        (OutputData::Bytes(data), false) => {
+            tensor.contents = Some(InferTensorContents {
+                bytes_contents: data.into_iter().map(String::into_bytes).collect(),
+                ..InferTensorContents::default()
+            });
+            (tensor, None)
+        }
+    }
+}
+
+/// The gRPC status for the HTTP status of `error`.
+fn status(error: ApiError) -> Status {
+    let code = match error.status_code().as_u16() {
+        400 | 415 => Code::InvalidArgument,
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        413 => Code::ResourceExhausted,
+        _ => Code::Internal,
+    };
+    Status::new(code, error.to_string())
+}
+
+/// `inference.GRPCInferenceService` on the models of the registry.
+pub struct InferenceService {
+    registry: web::Data<ModelRegistry>,
+    config: web::Data<Config>,
+}
+
+impl InferenceService {
+    pub fn new(registry: web::Data<ModelRegistry>, config: web::Data<Config>) -> InferenceService {
+        InferenceService { registry, config }
+    }
+
+    fn model(&self, name: &str) -> Result<Arc<Model>, ApiError> {
+        self.registry
+            .get(name)
+            .ok_or_else(|| ApiError::ModelNotFound(name.to_string()))
+    }
+
+    async fn infer(&self, request: ModelInferRequest) -> Result<ModelInferResponse, Status> {
+        let model = self.model(&request.model_name).map_err(status)?;
+        let raw = !request.raw_input_contents.is_empty();
+        let images = match request.inputs.as_slice() {
+            [input] => input_images(
+                input,
+                request.raw_input_contents.first().map(Vec::as_slice),
+                model.preprocess(),
+                &self.config.limits,
+            ),
+            inputs => Err(format!("expected 1 input, got {}", inputs.len())),
+        };
+        let (images, outputs) = images
+            .and_then(|images| Ok((images, output_kinds(&request)?)))
+            .map_err(|e| {
+                log::error!("func: grpc::model_infer: Error: {}", e);
+                Status::invalid_argument(e)
+            })?;
+        if images.is_empty() {
+            return Err(Status::invalid_argument("the input holds no images"));
+        }
+        let probabilities = v2::infer(&model, &images, &self.config.limits)
+            .await
+            .map_err(|e| {
+                log::error!("func: grpc::model_infer: Error: {}", e);
+                status(e)
+            })?;
+
+        let mut response = ModelInferResponse {
+            model_name: model.name().to_string(),
+            id: request.id,
+            ..ModelInferResponse::default()
+        };
+        for kind in outputs {
+            let output = v2::output(kind, &probabilities, &model)
+                .map_err(|e| Status::internal(e.to_string()))?;
+            let (tensor, raw_contents) = output_tensor(output, raw);
+            response.outputs.push(tensor);
+            response.raw_output_contents.extend(raw_contents);
+        }
+        log::info!(
+            "func: grpc::model_infer: model: {} images: {}",
+            model.name(),
+            images.len()
+        );
+        Ok(response)
+    }
+}
+
+#[tonic::async_trait]
+impl GrpcInferenceService for InferenceService {
+    async fn server_live(
+        &self,
+        _: Request<ServerLiveRequest>,
+    ) -> Result<Response<ServerLiveResponse>, Status> {
+        Ok(Response::new(ServerLiveResponse { live: true }))
+    }
+
+    async fn server_ready(
+        &self,
+        _: Request<ServerReadyRequest>,
+    ) -> Result<Response<ServerReadyResponse>, Status> {
+        Ok(Response::new(ServerReadyResponse {
+            ready: self.registry.is_ready(),
+        }))
+    }
+
+    async fn model_ready(
+        &self,
+        request: Request<ModelReadyRequest>,
+    ) -> Result<Response<ModelReadyResponse>, Status> {
+        let name = &request.get_ref().name;
+        let ready = self
+            .registry
+            .model_ready(name)
+            .ok_or_else(|| status(ApiError::ModelNotFound(name.clone())))?;
+        Ok(Response::new(ModelReadyResponse { ready }))
+    }
+
+    async fn server_metadata(
+        &self,
+        _: Request<ServerMetadataRequest>,
+    ) -> Result<Response<ServerMetadataResponse>, Status> {
+        Ok(Response::new(ServerMetadataResponse {
+            name: "rtorchdist".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            extensions: vec!["classification".to_string()],
+        }))
+    }
+
+    async fn model_metadata(
+        &self,
+        request: Request<ModelMetadataRequest>,
+    ) -> Result<Response<ModelMetadataResponse>, Status> {
+        let metadata = ModelMetadata::of(&*self.model(&request.get_ref().name).map_err(status)?);
+        let tensors = |tensors: Vec<v2::TensorMetadata>| {
+            tensors
+                .into_iter()
+                .map(|t| TensorMetadata {
+                    name: t.name,
+                    datatype: t.datatype,
+                    shape: t.shape,
+                })
+                .collect()
+        };
+        Ok(Response::new(ModelMetadataResponse {
+            name: metadata.name,
+            versions: Vec::new(),
+            platform: metadata.platform,
+            inputs: tensors(metadata.inputs),
+            outputs: tensors(metadata.outputs),
+        }))
+    }
+
+    async fn model_infer(
+        &self,
+        request: Request<ModelInferRequest>,
+    ) -> Result<Response<ModelInferResponse>, Status> {
+        self.infer(request.into_inner()).await.map(Response::new)
+    }
+}
+
+/// The running gRPC listener.
+pub struct GrpcServer {
+    shutdown: oneshot::Sender<()>,
+    /// Disconnected by the server thread once the server and its runtime stopped.
+    done: mpsc::Receiver<()>,
+    thread: JoinHandle<()>,
+}
+
+/// Binds `grpc.bind` and serves the registry on it from a thread with its own runtime of
+/// `server.workers` threads.
+pub fn start(
+    config: web::Data<Config>,
+    registry: web::Data<ModelRegistry>,
+) -> std::io::Result<GrpcServer> {
+    let bind = config.grpc.bind.clone().unwrap_or_default();
+    // Bound here, so a taken port fails the start like the HTTP server.
+    let listener = TcpListener::bind(bind.to_socket_addrs()?.collect::<Vec<_>>().as_slice())?;
+    listener.set_nonblocking(true)?;
+    log::info!("func: grpc::start: listening on {}", listener.local_addr()?);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.server.workers)
+        .thread_name("grpc")
+        .enable_all()
+        .build()?;
+    let service = GrpcInferenceServiceServer::new(InferenceService::new(registry, config.clone()))
+        .max_decoding_message_size(config.grpc.max_message_bytes)
+        .max_encoding_message_size(config.grpc.max_message_bytes);
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let (finished, done) = mpsc::channel::<()>();
+    let thread = thread::Builder::new()
+        .name("grpc-server".to_string())
+        .spawn(move || {
+            let _finished = finished;
+            let result = runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let incoming = TcpIncoming::from_listener(listener, true, None)
+                    .map_err(|e| std::io::Error::other(e.to_string()))?;
+                tonic::transport::Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming_shutdown(incoming, async {
+                        let _ = stopped.await;
+                    })
+                    .await
+                    .map_err(std::io::Error::other)
+            });
+            if let Err(e) = result {
+                log::error!("func: grpc::start: Error: {}", e);
+            }
+            drop(runtime);
+        })?;
+    Ok(GrpcServer {
+        shutdown,
+        done,
+        thread,
+    })
+}
+
+impl Listener for GrpcServer {
+    fn stop(self: Box<Self>, deadline: Instant) -> bool {
+        let _ = self.shutdown.send(());
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let stopped = !matches!(
+            self.done.recv_timeout(timeout),
+            Err(RecvTimeoutError::Timeout)
+        );
+        if stopped {
+            let _ = self.thread.join();
+            log::info!("func: grpc::stop: gRPC server stopped");
+        }
+        stopped
+    }
+}
@@ -0,0 +1,145 @@
+/*
+Model health for the liveness and readiness probes: a model is ready once it
+loaded and passed its self check, and stops being ready while it is reloaded or
+after repeated inference failures.
+ */
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+fn default_max_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_startup_self_check() -> bool {
+    true
+}
+
+/// `[health]` settings. The defaults are 5 failures and a startup self check, overridden by
+/// `MAX_INFERENCE_FAILURES` and `STARTUP_SELF_CHECK`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Inference failures in a row after which a model is no longer ready.
+    #[serde(default = "default_max_consecutive_failures")]
+    pub max_consecutive_failures: u32,
+    /// Run every model on the self check image before reporting it ready.
+    #[serde(default = "default_startup_self_check")]
+    pub startup_self_check: bool,
+}
+
+impl Default for HealthConfig {
+    fn default() -> HealthConfig {
+        HealthConfig {
+            max_consecutive_failures: default_max_consecutive_failures(),
+            startup_self_check: default_startup_self_check(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelStatus {
+    /// Loaded or being reloaded, the self check hasn't passed yet.
+    Loading,
+    Ready,
+    SelfCheckFailed,
+    /// `max_consecutive_failures` inferences in a row failed.
+    Failing,
+}
+
+/// Status of one model in `/readyz`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelHealthReport {
+    pub name: String,
+    pub status: ModelStatus,
+    pub ready: bool,
+    pub consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+struct HealthState {
+    status: ModelStatus,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+}
+
+/// Health of a loaded model, updated by its self check and every inference.
+pub struct ModelHealth {
+    max_consecutive_failures: u32,
+    state: Mutex<HealthState>,
+}
+
+impl ModelHealth {
+    /// A model that still has to pass its self check.
+    pub fn new(max_consecutive_failures: u32) -> ModelHealth {
+        ModelHealth {
+            max_consecutive_failures,
+            state: Mutex::new(HealthState {
+                status: ModelStatus::Loading,
+                consecutive_failures: 0,
+                last_error: None,
+            }),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut HealthState)) {
+        f(&mut self.state.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    /// Makes the model ready, also after it failed its self check or kept failing at inference.
+    pub fn self_check_passed(&self) {
+        self.update(|state| {
+            state.status = ModelStatus::Ready;
+            state.consecutive_failures = 0;
+            state.last_error = None;
+        });
+    }
+
+    pub fn self_check_failed(&self, error: String) {
+        self.update(|state| {
+            state.status = ModelStatus::SelfCheckFailed;
+            state.last_error = Some(error);
+        });
+    }
+
+    /// A successful inference clears the failure count and makes a failing model ready again.
+    pub fn record_success(&self) {
+        self.update(|state| {
+            state.consecutive_failures = 0;
+            if state.status == ModelStatus::Failing {
+                state.status = ModelStatus::Ready;
+            }
+        });
+    }
+
+    pub fn record_failure(&self, error: String) {
+        let max = self.max_consecutive_failures;
+        self.update(|state| {
+            state.consecutive_failures += 1;
+            state.last_error = Some(error);
+            if state.status == ModelStatus::Ready && state.consecutive_failures >= max {
+                log::error!(
+                    "func: ModelHealth::record_failure: {} failures in a row, model is not ready",
+                    state.consecutive_failures
+                );
+                state.status = ModelStatus::Failing;
+            }
+        });
+    }
+
+    pub fn status(&self) -> ModelStatus {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).status
+    }
+
+    pub fn report(&self, name: &str) -> ModelHealthReport {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        ModelHealthReport {
+            name: name.to_string(),
+            status: state.status,
+            ready: state.status == ModelStatus::Ready,
+            consecutive_failures: state.consecutive_failures,
+            last_error: state.last_error.clone(),
+        }
+    }
+}
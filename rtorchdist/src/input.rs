@@ -0,0 +1,92 @@
+/*
+Request bodies accepted by the predict routes besides multipart uploads.
+ */
+use actix_web::web;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::logic::PredictParams;
+use crate::upload::UploadError;
+
+/// `application/json` body of `/predict`: one `image_b64`, a list of base64 `images`, or a `url`
+/// for the server to fetch. `k` and `min_confidence` override the query parameters.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct JsonPredictRequest {
+    pub image_b64: Option<String>,
+    pub images: Option<Vec<String>>,
+    pub url: Option<String>,
+    pub k: Option<usize>,
+    pub min_confidence: Option<f64>,
+}
+
+/// Decoded images of a JSON request.
+#[derive(Debug, PartialEq)]
+pub enum JsonImages {
+    Single(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Url(String),
+}
+
+impl JsonPredictRequest {
+    pub fn params(&self, query: &PredictParams) -> PredictParams {
+        PredictParams {
+            k: self.k.or(query.k),
+            min_confidence: self.min_confidence.or(query.min_confidence),
+        }
+    }
+
+    pub fn images(&self) -> Result<JsonImages, String> {
+        match (&self.image_b64, &self.images, &self.url) {
+            (Some(image), None, None) => Ok(JsonImages::Single(decode_base64(image)?)),
+            (None, Some(images), None) if images.is_empty() => {
+                Err("images must not be empty".into())
+            }
+            (None, Some(images), None) => images
+                .iter()
+                .enumerate()
+                .map(|(i, image)| decode_base64(image).map_err(|e| format!("images[{}]: {}", i, e)))
+                .collect::<Result<_, _>>()
+                .map(JsonImages::List),
+            (None, None, Some(url)) => Ok(JsonImages::Url(url.clone())),
+            (None, None, None) => Err("the request needs image_b64, images or url".into()),
+            _ => Err("set only one of image_b64, images and url".into()),
+        }
+    }
+}
+
+/// Decodes standard base64, with or without a `data:image/...;base64,` prefix.
+pub fn decode_base64(data: &str) -> Result<Vec<u8>, String> {
+    let data = match data.strip_prefix("data:") {
+        Some(uri) => match uri.split_once(";base64,") {
+            Some((_, data)) => data,
+            None => return Err("data URI is not base64 encoded".into()),
+        },
+        None => data,
+    };
+    let data: String = data.split_whitespace().collect();
+    STANDARD
+        .decode(data)
+        .map_err(|e| format!("invalid base64: {}", e))
+}
+
+/// Largest JSON body accepted for `max_bytes` of images: base64 grows the data by a third.
+pub fn json_body_limit(max_bytes: usize) -> usize {
+    max_bytes / 3 * 4 + 64 * 1024
+}
+
+/// Reads a request body into memory, at most `limit` bytes.
+pub async fn read_body(mut payload: web::Payload, limit: usize) -> Result<Vec<u8>, UploadError> {
+    let mut body = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| UploadError::InvalidBody(e.to_string()))?;
+        if body.len() + chunk.len() > limit {
+            log::error!("func: read_body: body exceeds the limit of {} bytes", limit);
+            return Err(UploadError::PayloadTooLarge { limit });
+        }
+        body.extend_from_slice(&chunk);
+    }
+    log::info!("func: read_body: size: {:?}", body.len());
+    Ok(body)
+}
@@ -0,0 +1,108 @@
+/*
+Class names of a model's outputs, loaded from a labels file.
+ */
+use std::collections::BTreeMap;
+use std::fs;
+
+/// Number of ImageNet classes, the output size of the pre-trained `tch::vision` models.
+pub const IMAGENET_CLASS_COUNT: i64 = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Labels {
+    names: Vec<String>,
+}
+
+impl Labels {
+    pub fn new(names: Vec<String>) -> Labels {
+        Labels { names }
+    }
+
+    /// The 1000 ImageNet classes the pre-trained `tch::vision` models are trained on.
+    pub fn imagenet() -> Labels {
+        Labels::from_text(include_str!("imagenet_classes.txt"))
+    }
+
+    /// Class names are just the output indices, for models without a labels file.
+    pub fn numbered(count: usize) -> Labels {
+        Labels::new((0..count).map(|i| i.to_string()).collect())
+    }
+
+    /// Loads a labels file. `*.json` files hold either a list of names in output order or a
+    /// `{"<class id>": "<name>"}` object whose ids must cover `0..n`; any other file is plain
+    /// text with one name per line.
+    pub fn from_file(path: &str) -> Result<Labels, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read labels file {}: {}", path, e))?;
+        let labels = if path.ends_with(".json") {
+            Labels::from_json(&contents)
+        } else {
+            Ok(Labels::from_text(&contents))
+        }
+        .map_err(|e| format!("invalid labels file {}: {}", path, e))?;
+        if labels.is_empty() {
+            return Err(format!("labels file {} has no labels", path).into());
+        }
+        Ok(labels)
+    }
+
+    /// One label per line, blank lines at the end of the file are ignored.
+    pub fn from_text(contents: &str) -> Labels {
+        let mut names: Vec<String> = contents.lines().map(|l| l.trim().to_string()).collect();
+        while names.last().map(|n| n.is_empty()).unwrap_or(false) {
+            names.pop();
+        }
+        Labels::new(names)
+    }
+
+    pub fn from_json(contents: &str) -> Result<Labels, Box<dyn std::error::Error>> {
+        let value: serde_json::Value = serde_json::from_str(contents)?;
+        match value {
+            serde_json::Value::Array(_) => Ok(Labels::new(serde_json::from_value(value)?)),
+            serde_json::Value::Object(_) => {
+                let mapping: BTreeMap<String, String> = serde_json::from_value(value)?;
+                let mut by_id = BTreeMap::new();
+                let mut keys = BTreeMap::new();
+                for (key, name) in mapping {
+                    let id: usize = key
+                        .parse()
+                        .map_err(|_| format!("class id {:?} is not a number", key))?;
+                    // Keys such as "1" and "01" name the same class.
+                    if let Some(other) = keys.insert(id, key.clone()) {
+                        return Err(format!(
+                            "class ids {:?} and {:?} are both class {}",
+                            other, key, id
+                        )
+                        .into());
+                    }
+                    by_id.insert(id, name);
+                }
+                let count = by_id.len();
+                if let Some((id, _)) = by_id.iter().find(|(id, _)| **id >= count) {
+                    return Err(format!(
+                        "class ids must cover 0..{} without gaps, found {}",
+                        count, id
+                    )
+                    .into());
+                }
+                Ok(Labels::new(by_id.into_values().collect()))
+            }
+            _ => Err("expected a list of labels or a class id to label object".into()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Name of output `index`, or the index itself when out of range.
+    pub fn name(&self, index: i64) -> String {
+        match self.names.get(index as usize) {
+            Some(name) => name.clone(),
+            None => index.to_string(),
+        }
+    }
+}
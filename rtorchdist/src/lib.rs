@@ -1,2 +1,26 @@
+#[cfg(not(any(feature = "torch", feature = "onnx")))]
+compile_error!("rtorchdist needs a model backend, build with the `torch` or `onnx` feature");
+
+pub mod architecture;
+pub mod backend;
+pub mod batch;
+pub mod cli;
+pub mod config;
+pub mod error;
+pub mod fetch;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+pub mod input;
+pub mod labels;
 pub mod logic;
-pub mod routes;
\ No newline at end of file
+pub mod metrics;
+pub mod preprocess;
+pub mod registry;
+pub mod routes;
+pub mod shutdown;
+pub mod telemetry;
+pub mod tensor;
+pub mod tfserving;
+pub mod upload;
+pub mod v2;
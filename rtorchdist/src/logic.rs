@@ -1,213 +1,567 @@
 //use actix_multipart::Multipart;
+use futures::future::join_all;
 use serde::{Deserialize, Serialize};
-use std::env;
 use std::fs;
-use tch::nn::ModuleT;
-use tch::vision::imagenet;
-use tch::Kind;
-use tch::{Device, Tensor};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::Instrument;
 
+use crate::backend::{ModelBackend, ModelFormat};
+#[cfg(feature = "torch")]
+use crate::backend::{TorchScriptBackend, VarStoreBackend};
+use crate::batch::{BatchConfig, BatchInfo, Batcher};
+use crate::health::{HealthConfig, ModelHealth};
+use crate::labels::{Labels, IMAGENET_CLASS_COUNT};
+use crate::metrics::{metrics, Stage};
+use crate::preprocess::PreprocessConfig;
+use crate::registry::ModelSpec;
+use crate::tensor::{Kind, Tensor, TensorError};
+use crate::upload::{decode_image, UploadError, UploadLimits};
+
+/// One ranked class of a prediction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClassScore {
+    pub index: i64,
+    pub label: String,
+    pub probability: f64,
+}
+
+/// Ranked classes of a prediction, best first. `probabilities` and `classes` repeat `top` as
+/// parallel lists for existing clients.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Prediction {
     pub probabilities: Vec<f64>,
     pub classes: Vec<String>,
+    pub top: Vec<ClassScore>,
 }
 
-pub mod files {
-    use std::io::Write;
-
-    use actix_multipart::Multipart;
-    use actix_web::{web, Error};
-    use futures::{StreamExt, TryStreamExt};
-    use log::error;
-
-    pub async fn save_file(mut payload: Multipart, file_path: String) -> Result<String, Error> {
-        if let Ok(Some(mut field)) = payload.try_next().await {
-            let filepath = format!(".{}", file_path);
-            log::info!("func: save_file: filepath: {:?}", filepath);
-
-            let mut f = web::block({
-                let filepath_clone = filepath.clone();
-                move || std::fs::File::create(&filepath_clone)
-            })
-            .await
-            .map_err(|e| {
-                error!("Error creating file: {:?}", e);
-                actix_web::error::ErrorInternalServerError("Error creating file")
-            })?;
-
-            while let Some(chunk) = field.next().await {
-                let data = chunk.unwrap();
-                f = web::block({
-                    let _filepath_clone = filepath.clone();
-                    move || {
-                        let mut file = f?;
-                        file.write_all(&data)?;
-                        Ok(file)
-                    }
-                })
-                .await
-                .map_err(|e| {
-                    error!("Error writing to file: {:?}", e);
-                    actix_web::error::ErrorInternalServerError("Error writing to file")
-                })?;
-            }
-            let metadata = std::fs::metadata(&filepath).unwrap();
-            let file_size = metadata.len();
-            log::info!("func: save_file: file_size: {:?}", file_size);
-            return Ok(filepath);
+impl Prediction {
+    pub fn new(top: Vec<ClassScore>) -> Prediction {
+        Prediction {
+            probabilities: top.iter().map(|c| c.probability).collect(),
+            classes: top.iter().map(|c| c.label.clone()).collect(),
+            top,
         }
-
-        Err(actix_web::error::ErrorBadRequest("Error processing file"))
     }
 }
-/*Self check pre-trained model prediction */
-pub fn self_check_predict() -> Result<Prediction, Box<dyn std::error::Error>> {
-    log::info!("func: self_check_predict: starting");
-    let mut vs = tch::nn::VarStore::new(tch::Device::Cpu);
-    log::info!("func: self_check_predict: try loading image: tests/fixtures/lion.jpg");
-    let image_file = match env::var("IMAGE_PATH") {
-        Ok(path) => path,
-        Err(_) => "tests/fixtures/lion.jpg".to_string(),
-    };
-    let image = imagenet::load_image_and_resize224(&image_file).unwrap();
-    log::info!("func: self_check_predict: loading model: model/resnet34.ot");
-    let weight_file = match env::var("MODEL_PATH") {
-        Ok(path) => path,
-        Err(_) => "model/resnet34.ot".to_string(),
-    };
-    let resnet34 = tch::vision::resnet::resnet18(&vs.root(), imagenet::CLASS_COUNT);
-    vs.load(weight_file).unwrap();
-    log::info!("func: self_check_predict: applying forward pass of the model to get the logits and convert them to probabilities via a softmax");
-    let output = resnet34
-        .forward_t(&image.unsqueeze(0), /*train=*/ false)
-        .softmax(-1, Kind::Float);
 
-    for (probability, class) in imagenet::top(&output, 5).iter() {
-        println!("{:50} {:5.2}%", class, 100.0 * probability)
-    }
+fn default_top_k() -> usize {
+    1
+}
 
-    log::info!(
-        "func: self_check_predict: prediction results: {:?}",
-        imagenet::top(&output, 5)
-    );
+fn default_max_top_k() -> usize {
+    20
+}
 
-    let top_result = imagenet::top(&output, 1);
-    log::info!("Top result: {:?}", top_result);
-    let (probability, class) = top_result.first().unwrap(); // Swapped variables
-    log::info!("Class: {:?}", class);
-    log::info!("Confidence: {:?}", probability);
-    let confidence_f64 = *probability; // Directly use the probability value
+/// `[predict]`: how many classes predictions return. The defaults are 1 class, at most 20 and
+/// no minimum probability, overridden by `DEFAULT_TOP_K`, `MAX_TOP_K` and
+/// `DEFAULT_MIN_CONFIDENCE`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PredictConfig {
+    /// Number of classes returned when the request doesn't set `k`.
+    #[serde(default = "default_top_k")]
+    pub default_top_k: usize,
+    /// Largest `k` a request may ask for, larger values are clamped.
+    #[serde(default = "default_max_top_k")]
+    pub max_top_k: usize,
+    /// Lowest probability returned when the request doesn't set `min_confidence`.
+    #[serde(default)]
+    pub default_min_confidence: f64,
+}
 
-    log::info!("func: self_check_predict: prediction result: {:?}", class);
-    log::info!(
-        "func: self_check_predict: prediction result: {:?}",
-        confidence_f64
-    );
+impl Default for PredictConfig {
+    fn default() -> PredictConfig {
+        PredictConfig {
+            default_top_k: default_top_k(),
+            max_top_k: default_max_top_k(),
+            default_min_confidence: 0.0,
+        }
+    }
+}
 
-    let prediction = Prediction {
-        probabilities: vec![confidence_f64],
-        classes: vec![class.to_string()], // Updated variable
-    };
+/// The `k` and `min_confidence` a prediction is ranked with, see [`PredictParams::resolve`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ranking {
+    pub k: usize,
+    pub min_confidence: f64,
+}
 
-    log::info!(
-        "func: self_check_predict: prediction result: {:?}",
-        prediction
-    );
-    Ok(prediction)
+/// `?k=5&min_confidence=0.1` query parameters of the predict routes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct PredictParams {
+    pub k: Option<usize>,
+    pub min_confidence: Option<f64>,
 }
 
-pub async fn verify_image(image_path: String) -> Result<bool, Box<dyn std::error::Error>> {
-    if fs::metadata(&image_path).is_err() {
-        log::error!(
-            "func: predict_image: image file not found: {:?}",
-            image_path
+impl PredictParams {
+    /// Applies the server defaults and maximum of `config`.
+    pub fn resolve(&self, config: &PredictConfig) -> Result<Ranking, String> {
+        let k = self
+            .k
+            .unwrap_or(config.default_top_k)
+            .clamp(1, config.max_top_k.max(1));
+        let min_confidence = self.min_confidence.unwrap_or(config.default_min_confidence);
+        if !(0.0..=1.0).contains(&min_confidence) {
+            return Err(format!(
+                "min_confidence must be between 0 and 1, got {}",
+                min_confidence
+            ));
+        }
+        Ok(Ranking { k, min_confidence })
+    }
+}
+
+pub mod files {
+    use actix_multipart::{Field, Multipart};
+    use futures::TryStreamExt;
+
+    use crate::upload::UploadError;
+
+    /// One file field of a multipart upload, read into memory.
+    pub struct UploadedFile {
+        pub field: String,
+        pub filename: Option<String>,
+        pub bytes: Vec<u8>,
+    }
+
+    /// Reads one field into memory, failing once the upload has used up `remaining` bytes.
+    async fn read_field(
+        mut field: Field,
+        remaining: &mut usize,
+        limit: usize,
+    ) -> Result<UploadedFile, UploadError> {
+        let field_name = field.name().to_string();
+        let filename = field
+            .content_disposition()
+            .get_filename()
+            .map(|f| f.to_string());
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field
+            .try_next()
+            .await
+            .map_err(|e| UploadError::Multipart(e.to_string()))?
+        {
+            if chunk.len() > *remaining {
+                log::error!(
+                    "func: read_field: field: {:?} exceeds the upload limit",
+                    field_name
+                );
+                return Err(UploadError::PayloadTooLarge { limit });
+            }
+            *remaining -= chunk.len();
+            bytes.extend_from_slice(&chunk);
+        }
+        log::info!(
+            "func: read_field: field: {:?} filename: {:?} size: {:?}",
+            field_name,
+            filename,
+            bytes.len()
         );
-        return Err(format!("File not found: {}", image_path).into());
+        Ok(UploadedFile {
+            field: field_name,
+            filename,
+            bytes,
+        })
+    }
+
+    async fn next_field(payload: &mut Multipart) -> Result<Option<Field>, UploadError> {
+        payload
+            .try_next()
+            .await
+            .map_err(|e| UploadError::Multipart(e.to_string()))
     }
 
-    let metadata = fs::metadata(&image_path)?;
-    let file_size = metadata.len();
-    log::info!("Image path exists, size: {:?} bytes", file_size);
+    /// Reads the first field of a multipart upload into memory, at most `limit` bytes.
+    pub async fn read_file(
+        mut payload: Multipart,
+        limit: usize,
+    ) -> Result<UploadedFile, UploadError> {
+        let mut remaining = limit;
+        match next_field(&mut payload).await? {
+            Some(field) => read_field(field, &mut remaining, limit).await,
+            None => Err(UploadError::NoFiles),
+        }
+    }
 
-    Ok(true)
+    /// Reads every field of a multipart upload into memory, in upload order. `limit` bounds the
+    /// total size of all fields.
+    pub async fn read_files(
+        mut payload: Multipart,
+        limit: usize,
+    ) -> Result<Vec<UploadedFile>, UploadError> {
+        let mut remaining = limit;
+        let mut files = Vec::new();
+        while let Some(field) = next_field(&mut payload).await? {
+            files.push(read_field(field, &mut remaining, limit).await?);
+        }
+        if files.is_empty() {
+            return Err(UploadError::NoFiles);
+        }
+        Ok(files)
+    }
+}
+/// A pre-trained model loaded once at startup and shared between the actix
+/// workers through `web::Data`.
+pub struct Model {
+    name: String,
+    backend: Arc<dyn ModelBackend>,
+    batcher: Batcher,
+    labels: Labels,
+    preprocess: PreprocessConfig,
+    health: ModelHealth,
 }
 
-pub async fn predict_image(image_path: String) -> Result<Prediction, Box<dyn std::error::Error>> {
-    log::info!("route: /predict function: predict_image()");
-    log::info!("func: predict_image: loading image: {:?}", image_path);
-    //lets add logging to ensure that the image is loaded and the path is correct with error handling
-    let verify_image = match verify_image(image_path.clone()).await {
-        Ok(verify_image) => verify_image,
-        Err(error) => return Err(error.into()),
-    };
-    if !verify_image {
-        log::error!(
-            "func: predict_image: image file not found: {:?}",
-            image_path
+impl Model {
+    pub fn new(
+        name: &str,
+        backend: Arc<dyn ModelBackend>,
+        batch: BatchConfig,
+        labels: Labels,
+        preprocess: PreprocessConfig,
+        health: ModelHealth,
+    ) -> Model {
+        let batcher = Batcher::start(name, backend.clone(), batch);
+        Model {
+            name: name.to_string(),
+            backend,
+            batcher,
+            labels,
+            preprocess,
+            health,
+        }
+    }
+
+    /// Loads the model described by `spec` with the backend matching its format, batched with
+    /// `batching` and the overrides of the spec. It isn't ready until its self check passed.
+    pub fn load(
+        spec: &ModelSpec,
+        batching: &BatchConfig,
+        health: &HealthConfig,
+    ) -> Result<Model, Box<dyn std::error::Error>> {
+        let started = Instant::now();
+        log::info!(
+            "func: Model::load: loading model: {:?} format: {:?}",
+            spec.path,
+            spec.format
         );
-        return Err(actix_web::error::ErrorBadRequest("Image file not found").into());
+        let preprocess = spec.preprocess();
+        preprocess
+            .validate()
+            .map_err(|e| format!("model {}: {}", spec.name, e))?;
+        let labels = spec.load_labels()?;
+        let backend: Arc<dyn ModelBackend> = match spec.format {
+            #[cfg(feature = "torch")]
+            ModelFormat::VarStore => {
+                let architecture = spec.architecture()?;
+                let num_classes = spec.num_classes(labels.as_ref())?;
+                log::info!(
+                    "func: Model::load: architecture: {} classes: {}",
+                    architecture,
+                    num_classes
+                );
+                Arc::new(VarStoreBackend::load(
+                    &spec.path,
+                    architecture,
+                    num_classes,
+                )?)
+            }
+            #[cfg(feature = "torch")]
+            ModelFormat::TorchScript => Arc::new(TorchScriptBackend::load(&spec.path)?),
+            #[cfg(feature = "onnx")]
+            ModelFormat::Onnx => Arc::new(crate::backend::OnnxBackend::load(&spec.path)?),
+            #[cfg(not(all(feature = "torch", feature = "onnx")))]
+            format => {
+                return Err(format!(
+                    "model {} is a {:?} model, rebuild with `--features {}` to serve it",
+                    spec.name,
+                    format,
+                    format.feature()
+                )
+                .into())
+            }
+        };
+        // Run a blank image through the model to learn its output size.
+        let size = preprocess.size;
+        let (height, width) = (size.height() as i64, size.width() as i64);
+        let blank = Tensor::of_slice(&vec![0f32; (3 * height * width) as usize])
+            .view([1, 3, height, width]);
+        let output_size = *backend
+            .forward(&blank)
+            .map_err(|e| format!("model {} failed a test forward pass: {}", spec.name, e))?
+            .size()
+            .last()
+            .unwrap_or(&0) as usize;
+        let labels = match labels {
+            Some(labels) if labels.len() != output_size => {
+                return Err(format!(
+                    "labels file {} has {} labels but model {} outputs {} classes",
+                    spec.labels.as_deref().unwrap_or_default(),
+                    labels.len(),
+                    spec.name,
+                    output_size
+                )
+                .into())
+            }
+            Some(labels) => labels,
+            None if output_size as i64 == IMAGENET_CLASS_COUNT => Labels::imagenet(),
+            None => Labels::numbered(output_size),
+        };
+        log::info!(
+            "func: Model::load: model loaded: {:?} classes: {} in {:?}",
+            spec.path,
+            output_size,
+            started.elapsed()
+        );
+        metrics()
+            .model_load
+            .with_label_values(&[&spec.name])
+            .set(started.elapsed().as_secs_f64());
+        Ok(Model::new(
+            &spec.name,
+            backend,
+            spec.batch.apply(batching),
+            labels,
+            preprocess,
+            ModelHealth::new(health.max_consecutive_failures),
+        ))
     }
 
-    let image = match imagenet::load_image_and_resize224(&image_path) {
-        Ok(image) => image,
-        Err(error) => return Err(error.into()),
-    };
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-    log::info!("func: predict_image: starting");
-    let mut vs = tch::nn::VarStore::new(tch::Device::Cpu);
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
 
-    log::info!("func: predict_image: loading model: model/resnet34.ot");
-    let weight_file = match env::var("MODEL_PATH") {
-        Ok(path) => path,
-        Err(_) => "model/resnet34.ot".to_string(),
-    };
-    let resnet34 = tch::vision::resnet::resnet18(&vs.root(), imagenet::CLASS_COUNT);
-    vs.load(weight_file).unwrap();
-    log::info!("func: predict_image:  applying forward pass of the model to get the logits and convert them to probabilities via a softmax");
-    let output = resnet34
-        .forward_t(&image.unsqueeze(0), /*train=*/ false)
-        .softmax(-1, Kind::Float);
+    pub fn format(&self) -> ModelFormat {
+        self.backend.format()
+    }
 
-    for (probability, class) in imagenet::top(&output, 5).iter() {
-        println!("{:50} {:5.2}%", class, 100.0 * probability)
+    pub fn preprocess(&self) -> &PreprocessConfig {
+        &self.preprocess
     }
 
-    log::info!(
-        "func: predict_image: : prediction results: {:?}",
-        imagenet::top(&output, 5)
-    );
+    pub fn health(&self) -> &ModelHealth {
+        &self.health
+    }
+
+    pub fn batch_info(&self) -> BatchInfo {
+        self.batcher.info()
+    }
+
+    /// Applies a forward pass of the model to a single image to get the logits and convert them
+    /// to probabilities via a softmax, bypassing the batching queue.
+    pub fn forward(&self, image: &Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        let logits = self.backend.forward(&image.unsqueeze(0))?;
+        Ok(logits.softmax(-1, Kind::Float))
+    }
+
+    /// Queues a single image for the next batched forward pass and returns its probabilities.
+    /// Failures count against the model's readiness.
+    pub async fn infer(&self, image: Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        let output = self.batcher.submit(image).await;
+        match &output {
+            Ok(_) => self.health.record_success(),
+            Err(e) => self.health.record_failure(e.to_string()),
+        }
+        output
+    }
+
+    /// Stops queueing images and waits for the queued ones until `deadline`, see
+    /// [`Batcher::shutdown`].
+    pub fn shutdown(&self, deadline: Instant) -> bool {
+        self.batcher.shutdown(deadline)
+    }
+}
 
-    let top_result = imagenet::top(&output, 1);
-    log::info!("Top result: {:?}", top_result);
-    let (probability, class) = top_result.first().unwrap(); // Swapped variables
-    log::info!("Class: {:?}", class);
-    log::info!("Confidence: {:?}", probability);
-    let confidence_f64 = *probability; // Directly use the probability value
+/// The `k` most likely classes of a probability tensor, dropping those below `min_confidence`.
+pub fn rank(
+    output: &Tensor,
+    k: usize,
+    min_confidence: f64,
+    labels: &Labels,
+) -> Result<Vec<ClassScore>, TensorError> {
+    let output = output.f_view([-1])?;
+    let k = (k as i64).min(output.size()[0]);
+    let (values, indices) = output.f_topk(k, -1, true, true)?;
+    let values = Vec::<f64>::from(&values);
+    let indices = Vec::<i64>::from(&indices);
+    Ok(values
+        .into_iter()
+        .zip(indices)
+        .filter(|(probability, _)| *probability >= min_confidence)
+        .map(|(probability, index)| ClassScore {
+            index,
+            label: labels.name(index),
+            probability,
+        })
+        .collect())
+}
 
-    log::info!("func: predict_image: : prediction result: {:?}", class);
+fn top_prediction(
+    model: &Model,
+    output: &Tensor,
+    ranking: Ranking,
+) -> Result<Prediction, Box<dyn std::error::Error>> {
+    let top = rank(output, ranking.k, ranking.min_confidence, model.labels())?;
+    log::info!("func: top_prediction: prediction results: {:?}", top);
+    Ok(Prediction::new(top))
+}
+
+/*Self check pre-trained model prediction on the fixture image `image_file` */
+pub fn self_check_predict(
+    model: &Model,
+    image_file: &str,
+) -> Result<Prediction, Box<dyn std::error::Error>> {
+    log::info!("func: self_check_predict: starting");
     log::info!(
-        "func: predict_image: : prediction result: {:?}",
-        confidence_f64
+        "func: self_check_predict: try loading image: {:?}",
+        image_file
     );
-
-    let prediction = Prediction {
-        probabilities: vec![confidence_f64],
-        classes: vec![class.to_string()], // Updated variable
+    let image = model
+        .preprocess()
+        .tensor_from_memory(&fs::read(image_file)?)?;
+    log::info!("func: self_check_predict: applying forward pass of the model to get the logits and convert them to probabilities via a softmax");
+    let output = model.forward(&image)?;
+    let ranking = Ranking {
+        k: 1,
+        min_confidence: 0.0,
     };
+    let prediction = top_prediction(model, &output, ranking)?;
 
-    log::info!("func: predict_image: : prediction result: {:?}", prediction);
+    log::info!(
+        "func: self_check_predict: prediction result: {:?}",
+        prediction
+    );
     Ok(prediction)
 }
 
+/// Runs a preprocessed `[C, H, W]` image through the batched forward pass of `model`, timed as
+/// the forward stage and counted by its top-1 class, and returns its class probabilities.
+pub async fn infer_image(
+    model: &Model,
+    image: Tensor,
+) -> Result<Tensor, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let output = model
+        .infer(image)
+        .instrument(Stage::Forward.span(model.name()))
+        .await?;
+    let metrics = metrics();
+    metrics.observe(Stage::Forward, model.name(), started);
+    if let Some(top) = rank(&output, 1, 0.0, model.labels())?.first() {
+        metrics
+            .predicted_classes
+            .with_label_values(&[model.name(), &top.label])
+            .inc();
+    }
+    Ok(output)
+}
+
+/// Decodes, preprocesses and runs an encoded image through `model`. Images outside `limits`
+/// fail with an [`UploadError`] before they are decoded.
+pub async fn infer_bytes(
+    model: &Model,
+    bytes: &[u8],
+    limits: &UploadLimits,
+) -> Result<Tensor, Box<dyn std::error::Error>> {
+    let metrics = metrics();
+    let started = Instant::now();
+    let image = Stage::Decode
+        .span(model.name())
+        .in_scope(|| decode_image(bytes, limits))?;
+    metrics.observe(Stage::Decode, model.name(), started);
+    let started = Instant::now();
+    let image = Stage::Preprocess
+        .span(model.name())
+        .in_scope(|| model.preprocess().tensor(&image));
+    metrics.observe(Stage::Preprocess, model.name(), started);
+    infer_image(model, image).await
+}
+
+/// Runs an in-memory image through `model`. Images outside `limits` fail with an
+/// [`UploadError`] before they are decoded.
+pub async fn predict_bytes(
+    model: &Model,
+    bytes: &[u8],
+    ranking: Ranking,
+    limits: &UploadLimits,
+) -> Result<Prediction, Box<dyn std::error::Error>> {
+    let output = infer_bytes(model, bytes, limits).await?;
+    top_prediction(model, &output, ranking)
+}
+
+/// Result for one image of a batch upload, reported per item so one bad image doesn't fail
+/// the others.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchItem {
+    pub field: String,
+    pub filename: Option<String>,
+    pub status: String,
+    /// Error code of an image refused by the upload limits.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Prediction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Predicts every uploaded image concurrently so they share forward passes in the batcher.
+pub async fn predict_files(
+    model: &Model,
+    files: Vec<files::UploadedFile>,
+    ranking: Ranking,
+    limits: &UploadLimits,
+) -> Vec<BatchItem> {
+    let predictions = join_all(
+        files
+            .iter()
+            .map(|f| predict_bytes(model, &f.bytes, ranking, limits)),
+    )
+    .await;
+    files
+        .into_iter()
+        .zip(predictions)
+        .map(|(file, prediction)| match prediction {
+            Ok(prediction) => BatchItem {
+                field: file.field,
+                filename: file.filename,
+                status: "success".to_string(),
+                code: None,
+                result: Some(prediction),
+                message: None,
+            },
+            Err(e) => {
+                log::error!("func: predict_files: field: {:?} error: {}", file.field, e);
+                // Images refused by the upload limits keep their error code.
+                let code = e
+                    .downcast_ref::<UploadError>()
+                    .map(|e| e.code().to_string());
+                BatchItem {
+                    field: file.field,
+                    filename: file.filename,
+                    status: "error".to_string(),
+                    code,
+                    result: None,
+                    message: Some(format!("Prediction failed with error: {}", e)),
+                }
+            }
+        })
+        .collect()
+}
+
 /*
 Self check code to verify PyTorch works without models
  */
 
 /*A PyTorch self-tests function */
+#[cfg(feature = "torch")]
 pub fn tensor_device_cpu() -> String {
     let t = Tensor::of_slice(&[3, 1, 4]);
-    let t = t.to_device(Device::Cpu);
+    let t = t.to_device(tch::Device::Cpu);
     //log descriptive information about the tensor via log::info!
     log::info!("Tensor t: {:?}", t);
     log::info!("Tensor t size: {:?}", t.size());
@@ -1,26 +1,103 @@
-use actix_web::middleware::Logger;
-use actix_web::{App, HttpServer};
-use log::LevelFilter;
+use actix_web::middleware::{from_fn, Logger};
+use actix_web::{web, App, HttpServer};
+use clap::Parser;
+use std::time::Duration;
 
-mod logic;
-mod routes;
+use rtorchdist::cli::{self, Cli, Command};
+use rtorchdist::config::Config;
+use rtorchdist::logic::PredictParams;
+use rtorchdist::metrics;
+use rtorchdist::routes;
+use rtorchdist::shutdown;
+use rtorchdist::telemetry;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::Builder::from_default_env()
-        .filter_level(LevelFilter::Debug)
-        .init();
+    let cli = Cli::parse();
+    let config = match Config::load(&cli.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = telemetry::init(&config.logging, &config.tracing) {
+        eprintln!("cannot set up logging: {}", e);
+        std::process::exit(2);
+    }
+    log::info!("func: main: configuration: {:?}", config);
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config).await,
+        Command::Predict {
+            files,
+            model_name,
+            k,
+            min_confidence,
+        } => {
+            let params = PredictParams { k, min_confidence };
+            let code = cli::predict(&config, &files, model_name.as_deref(), &params).await;
+            std::process::exit(code)
+        }
+        Command::Check => std::process::exit(cli::check(&config)),
+    }
+}
+
+async fn serve(config: Config) -> std::io::Result<()> {
     println!("Starting pytorch model server...");
-    HttpServer::new(|| {
+    let registry = match cli::load_registry(&config) {
+        Ok(registry) => web::Data::new(registry),
+        Err(e) => {
+            log::error!("Failed to load models: {}", e);
+            return Err(std::io::Error::other(format!(
+                "failed to load models: {}",
+                e
+            )));
+        }
+    };
+    // `/readyz` reports the models as loading until their self check passed. Models failing it
+    // are checked again in the background, the probes only read their status.
+    {
+        let registry = registry.clone();
+        let image_file = config.models.self_check_image.clone();
+        std::thread::spawn(move || registry.check_in_background(&image_file));
+    }
+    let server_config = config.server.clone();
+    let features = config.features;
+    let config = web::Data::new(config);
+    let (app_registry, app_config) = (registry.clone(), config.clone());
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
-            .service(routes::index)
-            .service(routes::check_image_prediction)
-            .service(routes::check_image_upload)
-            .service(routes::check_pytorch_cpu)
-            .service(routes::predict)
+            .wrap(from_fn(metrics::track_requests))
+            // Outermost, so the access log and handlers run in the request span.
+            .wrap(from_fn(telemetry::request_context))
+            .app_data(app_registry.clone())
+            .app_data(app_config.clone())
+            .app_data(routes::query_config())
+            .configure(|cfg| routes::services(cfg, &features))
     })
-    .bind("0.0.0.0:8080")?
-    .run()
+    .workers(server_config.workers)
+    .backlog(server_config.backlog)
+    .keep_alive(Duration::from_secs(server_config.keep_alive_secs))
+    .shutdown_timeout(server_config.shutdown_timeout_secs)
+    // Signals are handled by `shutdown::serve`, which fails readiness first.
+    .disable_signals()
+    .bind(&server_config.bind)?
+    .run();
+    #[cfg(feature = "grpc")]
+    let listeners: Vec<Box<dyn shutdown::Listener>> = if config.grpc.bind.is_some() {
+        vec![Box::new(rtorchdist::grpc::start(config, registry.clone())?)]
+    } else {
+        Vec::new()
+    };
+    #[cfg(not(feature = "grpc"))]
+    let listeners = Vec::new();
+    shutdown::serve(
+        server,
+        listeners,
+        registry,
+        &server_config,
+        shutdown::signal(),
+    )
     .await
 }
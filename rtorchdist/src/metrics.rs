@@ -0,0 +1,213 @@
+/*
+Prometheus metrics served on `/metrics`: request counts, per-stage latencies,
+batch sizes, model load durations, in-flight requests and predicted classes.
+ */
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use prometheus::{
+    exponential_buckets, Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::batch::BATCH_SIZE_LIMIT;
+use crate::registry::ModelRegistry;
+
+/// Stages of a prediction timed in `rtorchdist_stage_duration_seconds`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Reading the request body or multipart upload.
+    Upload,
+    /// Checking and decoding the image.
+    Decode,
+    /// Resizing and normalizing the image into the input tensor.
+    Preprocess,
+    /// Waiting for and running the batched forward pass.
+    Forward,
+    /// Building the JSON response.
+    Serialize,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Upload => "upload",
+            Stage::Decode => "decode",
+            Stage::Preprocess => "preprocess",
+            Stage::Forward => "forward",
+            Stage::Serialize => "serialize",
+        }
+    }
+
+    /// Tracing span of this stage for `model`, a child of the current request span.
+    pub fn span(&self, model: &str) -> tracing::Span {
+        match self {
+            Stage::Upload => tracing::info_span!("upload", model),
+            Stage::Decode => tracing::info_span!("decode", model),
+            Stage::Preprocess => tracing::info_span!("preprocess", model),
+            Stage::Forward => tracing::info_span!("forward", model),
+            Stage::Serialize => tracing::info_span!("serialize", model),
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    /// `rtorchdist_http_requests_total{route, model, status}`
+    pub requests: IntCounterVec,
+    /// `rtorchdist_http_requests_in_flight`
+    pub in_flight: IntGauge,
+    /// `rtorchdist_stage_duration_seconds{stage, model}`
+    pub stages: HistogramVec,
+    /// `rtorchdist_batch_size{model}`, images per forward pass.
+    pub batch_sizes: HistogramVec,
+    /// `rtorchdist_model_load_duration_seconds{model}`, of the last load.
+    pub model_load: GaugeVec,
+    /// `rtorchdist_predicted_class_total{model, class}`, the top-1 class of each prediction.
+    pub predicted_classes: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "rtorchdist_http_requests_total",
+                "HTTP requests by route, model and status",
+            ),
+            &["route", "model", "status"],
+        )
+        .unwrap();
+        let in_flight = IntGauge::new(
+            "rtorchdist_http_requests_in_flight",
+            "HTTP requests being handled",
+        )
+        .unwrap();
+        let stages = HistogramVec::new(
+            HistogramOpts::new(
+                "rtorchdist_stage_duration_seconds",
+                "Time spent per prediction stage",
+            )
+            // 0.5ms to 16s.
+            .buckets(exponential_buckets(0.0005, 2.0, 16).unwrap()),
+            &["stage", "model"],
+        )
+        .unwrap();
+        let batch_sizes = HistogramVec::new(
+            HistogramOpts::new(
+                "rtorchdist_batch_size",
+                "Images sent through each batched forward pass",
+            )
+            // 1 to the largest max_batch_size.
+            .buckets(
+                exponential_buckets(1.0, 2.0, BATCH_SIZE_LIMIT.trailing_zeros() as usize + 1)
+                    .unwrap(),
+            ),
+            &["model"],
+        )
+        .unwrap();
+        let model_load = GaugeVec::new(
+            Opts::new(
+                "rtorchdist_model_load_duration_seconds",
+                "Duration of the last load of each model",
+            ),
+            &["model"],
+        )
+        .unwrap();
+        let predicted_classes = IntCounterVec::new(
+            Opts::new(
+                "rtorchdist_predicted_class_total",
+                "Predictions by model and top-1 class",
+            ),
+            &["model", "class"],
+        )
+        .unwrap();
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        registry.register(Box::new(stages.clone())).unwrap();
+        registry.register(Box::new(batch_sizes.clone())).unwrap();
+        registry.register(Box::new(model_load.clone())).unwrap();
+        registry
+            .register(Box::new(predicted_classes.clone()))
+            .unwrap();
+        Metrics {
+            registry,
+            requests,
+            in_flight,
+            stages,
+            batch_sizes,
+            model_load,
+            predicted_classes,
+        }
+    }
+
+    /// Records the time since `started` for `stage` of `model`.
+    pub fn observe(&self, stage: Stage, model: &str, started: Instant) {
+        self.stages
+            .with_label_values(&[stage.as_str(), model])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("func: Metrics::render: Error: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// The metrics of this process.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Model a request went to: the `{name}` of the route if the registry has such a model, else
+/// `unknown` so made up names don't add labels, the default model for the other predict
+/// routes, otherwise none.
+fn model_label(response: &ServiceResponse<impl MessageBody>, route: &str) -> String {
+    let request = response.request();
+    let registry = request.app_data::<web::Data<ModelRegistry>>();
+    if let Some(name) = request.match_info().get("name") {
+        return match registry {
+            Some(registry) if registry.get(name).is_some() => name.to_string(),
+            _ => "unknown".to_string(),
+        };
+    }
+    if route.starts_with("/predict") {
+        if let Some(registry) = registry {
+            return registry.default_name().to_string();
+        }
+    }
+    String::new()
+}
+
+/// Middleware counting requests and those in flight, wrapped around the app with
+/// `actix_web::middleware::from_fn(metrics::track_requests)`.
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let metrics = metrics();
+    metrics.in_flight.inc();
+    let response = next.call(req).await;
+    metrics.in_flight.dec();
+    if let Ok(response) = &response {
+        // Routes are counted by pattern so paths with model names don't add labels.
+        let route = response
+            .request()
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        let model = model_label(response, &route);
+        metrics
+            .requests
+            .with_label_values(&[&route, &model, response.status().as_str()])
+            .inc();
+    }
+    response
+}
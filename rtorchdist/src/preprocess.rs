@@ -0,0 +1,230 @@
+/*
+Per-model image preprocessing: decode, resize, crop and normalise an image
+into the `[C, H, W]` float tensor the model expects.
+ */
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+
+use crate::tensor::Tensor;
+
+/// How the image is fitted to the model input.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeStrategy {
+    /// Resize both sides to `resize_size`, ignoring the aspect ratio.
+    Stretch,
+    /// Resize the shorter side to `resize_size`, keeping the aspect ratio.
+    ShorterSide,
+    /// Fit the whole image inside `size` keeping the aspect ratio, padding the rest with
+    /// `pad_color`.
+    Letterbox,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelOrder {
+    Rgb,
+    Bgr,
+}
+
+/// Resampling filter, see `image::imageops::FilterType`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    Nearest,
+    /// Bilinear.
+    Triangle,
+    /// Bicubic.
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<Filter> for FilterType {
+    fn from(filter: Filter) -> FilterType {
+        match filter {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::CatmullRom => FilterType::CatmullRom,
+            Filter::Gaussian => FilterType::Gaussian,
+            Filter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// `[width, height]`, written as a single number for square sizes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Size {
+    Square(u32),
+    WidthHeight([u32; 2]),
+}
+
+impl Size {
+    pub fn width(&self) -> u32 {
+        match self {
+            Size::Square(side) => *side,
+            Size::WidthHeight([width, _]) => *width,
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        match self {
+            Size::Square(side) => *side,
+            Size::WidthHeight([_, height]) => *height,
+        }
+    }
+}
+
+/// `"preprocess"` in the model manifest. The default matches
+/// `tch::vision::imagenet::load_image_and_resize224`: shorter side resized to 224, a 224x224
+/// center crop and the ImageNet mean/std.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PreprocessConfig {
+    pub resize: ResizeStrategy,
+    /// Model input size.
+    pub size: Size,
+    /// Size the image is resized to before the center crop, `size` when omitted. E.g. `256`
+    /// with a `224` size for the usual torchvision resize-then-crop.
+    pub resize_size: Option<Size>,
+    /// Crop the center `size` out of the resized image. Required by `shorter_side`, ignored by
+    /// `letterbox`.
+    pub center_crop: bool,
+    /// Pixel values are multiplied by `scale` before the mean/std normalisation.
+    pub scale: f32,
+    /// Per-channel mean, in `channel_order`.
+    pub mean: [f32; 3],
+    /// Per-channel standard deviation, in `channel_order`.
+    pub std: [f32; 3],
+    pub channel_order: ChannelOrder,
+    pub filter: Filter,
+    pub pad_color: [u8; 3],
+}
+
+impl Default for PreprocessConfig {
+    fn default() -> PreprocessConfig {
+        PreprocessConfig {
+            resize: ResizeStrategy::ShorterSide,
+            size: Size::Square(224),
+            resize_size: None,
+            center_crop: true,
+            scale: 1.0 / 255.0,
+            mean: [0.485, 0.456, 0.406],
+            std: [0.229, 0.224, 0.225],
+            channel_order: ChannelOrder::Rgb,
+            filter: Filter::Triangle,
+            pad_color: [0, 0, 0],
+        }
+    }
+}
+
+impl PreprocessConfig {
+    /// The default preprocessing with a square input of `side` pixels.
+    pub fn square(side: u32) -> PreprocessConfig {
+        PreprocessConfig {
+            size: Size::Square(side),
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let resize_size = self.resize_size.unwrap_or(self.size);
+        for size in [self.size, resize_size] {
+            if size.width() == 0 || size.height() == 0 {
+                return Err("preprocess sizes must be positive".to_string());
+            }
+        }
+        if self.std.contains(&0.0) {
+            return Err("preprocess std must not be zero".to_string());
+        }
+        if self.resize == ResizeStrategy::ShorterSide && !self.center_crop {
+            return Err("preprocess resize shorter_side needs center_crop".to_string());
+        }
+        if self.resize != ResizeStrategy::Letterbox
+            && (resize_size.width() < self.size.width()
+                || resize_size.height() < self.size.height())
+        {
+            return Err("preprocess resize_size must not be smaller than size".to_string());
+        }
+        if self.resize == ResizeStrategy::Stretch && !self.center_crop && resize_size != self.size {
+            return Err("preprocess resize_size differs from size without center_crop".to_string());
+        }
+        Ok(())
+    }
+
+    /// Resizes and crops `image` to `size`.
+    pub fn fit(&self, image: &DynamicImage) -> RgbImage {
+        let image = image.to_rgb8();
+        let filter = self.filter.into();
+        let (out_w, out_h) = (self.size.width(), self.size.height());
+        let resize_size = self.resize_size.unwrap_or(self.size);
+        let resized = match self.resize {
+            ResizeStrategy::Stretch => {
+                imageops::resize(&image, resize_size.width(), resize_size.height(), filter)
+            }
+            ResizeStrategy::ShorterSide => {
+                let (w, h) = image.dimensions();
+                // Scale so that both sides cover the resize size, like torchvision's Resize.
+                let ratio = f64::max(
+                    resize_size.width() as f64 / w as f64,
+                    resize_size.height() as f64 / h as f64,
+                );
+                let new_w = ((w as f64 * ratio).round() as u32).max(resize_size.width());
+                let new_h = ((h as f64 * ratio).round() as u32).max(resize_size.height());
+                imageops::resize(&image, new_w, new_h, filter)
+            }
+            ResizeStrategy::Letterbox => {
+                let (w, h) = image.dimensions();
+                let ratio = f64::min(out_w as f64 / w as f64, out_h as f64 / h as f64);
+                let new_w = ((w as f64 * ratio).round() as u32).clamp(1, out_w);
+                let new_h = ((h as f64 * ratio).round() as u32).clamp(1, out_h);
+                let resized = imageops::resize(&image, new_w, new_h, filter);
+                let mut canvas = RgbImage::from_pixel(out_w, out_h, Rgb(self.pad_color));
+                imageops::overlay(
+                    &mut canvas,
+                    &resized,
+                    ((out_w - new_w) / 2) as i64,
+                    ((out_h - new_h) / 2) as i64,
+                );
+                return canvas;
+            }
+        };
+        let (w, h) = resized.dimensions();
+        if !self.center_crop || (w == out_w && h == out_h) {
+            return resized;
+        }
+        imageops::crop_imm(&resized, (w - out_w) / 2, (h - out_h) / 2, out_w, out_h).to_image()
+    }
+
+    /// Scales and normalises `image` into `[C, H, W]` values in `channel_order`.
+    pub fn normalize(&self, image: &RgbImage) -> Vec<f32> {
+        let (w, h) = image.dimensions();
+        let plane = (w * h) as usize;
+        let channels = match self.channel_order {
+            ChannelOrder::Rgb => [0, 1, 2],
+            ChannelOrder::Bgr => [2, 1, 0],
+        };
+        let mut data = vec![0f32; 3 * plane];
+        for (i, pixel) in image.pixels().enumerate() {
+            for (c, source) in channels.iter().enumerate() {
+                let value = pixel[*source] as f32 * self.scale;
+                data[c * plane + i] = (value - self.mean[c]) / self.std[c];
+            }
+        }
+        data
+    }
+
+    /// Decodes an encoded image and turns it into the model input tensor.
+    pub fn tensor_from_memory(&self, bytes: &[u8]) -> Result<Tensor, Box<dyn std::error::Error>> {
+        let image = image::load_from_memory(bytes)?;
+        Ok(self.tensor(&image))
+    }
+
+    pub fn tensor(&self, image: &DynamicImage) -> Tensor {
+        let fitted = self.fit(image);
+        let data = self.normalize(&fitted);
+        Tensor::of_slice(&data).view([3, self.size.height() as i64, self.size.width() as i64])
+    }
+}
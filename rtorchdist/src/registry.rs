@@ -0,0 +1,599 @@
+/*
+Model registry: every model the server can serve, loaded at startup and
+reloaded on request.
+ */
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::architecture::Architecture;
+use crate::backend::ModelFormat;
+use crate::batch::{BatchConfig, BatchInfo, BatchOverrides};
+use crate::health::{HealthConfig, ModelHealthReport, ModelStatus};
+use crate::labels::{Labels, IMAGENET_CLASS_COUNT};
+use crate::logic::{self_check_predict, Model};
+use crate::preprocess::PreprocessConfig;
+
+/// First and longest wait between self checks of models that failed theirs.
+const RECHECK_MIN_WAIT: Duration = Duration::from_secs(5);
+const RECHECK_MAX_WAIT: Duration = Duration::from_secs(300);
+
+fn default_model_dir() -> String {
+    "model".to_string()
+}
+
+fn default_self_check_image() -> String {
+    "tests/fixtures/lion.jpg".to_string()
+}
+
+/// Where the models to serve come from, `[models]` in the config file. The defaults are the
+/// `model` directory without a manifest path or single model, and `tests/fixtures/lion.jpg`,
+/// overridden by `MODEL_DIR`, `MODEL_MANIFEST`, `MODEL_PATH` and `IMAGE_PATH`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ModelsConfig {
+    /// Directory scanned for model files when no manifest is present.
+    #[serde(default = "default_model_dir")]
+    pub dir: String,
+    /// Path of the optional model manifest, `<dir>/manifest.json` when omitted.
+    #[serde(default)]
+    pub manifest: Option<String>,
+    /// A single weight file to serve instead of the manifest or directory.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Image run through the default model by the self check.
+    #[serde(default = "default_self_check_image")]
+    pub self_check_image: String,
+}
+
+impl Default for ModelsConfig {
+    fn default() -> ModelsConfig {
+        ModelsConfig {
+            dir: default_model_dir(),
+            manifest: None,
+            path: None,
+            self_check_image: default_self_check_image(),
+        }
+    }
+}
+
+impl ModelsConfig {
+    pub fn manifest_path(&self) -> String {
+        match &self.manifest {
+            Some(path) => path.clone(),
+            None => format!("{}/manifest.json", self.dir),
+        }
+    }
+}
+
+/// One model to load, as listed in the manifest or found in the model directory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelSpec {
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub format: ModelFormat,
+    /// Network the weights were exported from, guessed from the model name when omitted. Only
+    /// used by the `var_store` format.
+    #[serde(default)]
+    pub architecture: Option<Architecture>,
+    /// Size of the model output, taken from the labels file when omitted, otherwise 1000.
+    #[serde(default)]
+    pub num_classes: Option<i64>,
+    /// Labels file naming each output class, the ImageNet classes when omitted.
+    #[serde(default)]
+    pub labels: Option<String>,
+    #[serde(default)]
+    pub batch: BatchOverrides,
+    /// How uploaded images are turned into model input, the torchvision ImageNet preprocessing
+    /// when omitted.
+    #[serde(default)]
+    pub preprocess: Option<PreprocessConfig>,
+}
+
+impl ModelSpec {
+    pub fn new(name: String, path: String) -> ModelSpec {
+        let format = Path::new(&path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(ModelFormat::from_extension)
+            .unwrap_or_default();
+        ModelSpec {
+            name,
+            path,
+            format,
+            architecture: None,
+            num_classes: None,
+            labels: None,
+            batch: BatchOverrides::default(),
+            preprocess: None,
+        }
+    }
+
+    pub fn load_labels(&self) -> Result<Option<Labels>, Box<dyn std::error::Error>> {
+        match &self.labels {
+            Some(path) => Ok(Some(Labels::from_file(path)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Number of classes the architecture is built with: the manifest `num_classes`, the number
+    /// of labels, or the 1000 ImageNet classes.
+    pub fn num_classes(&self, labels: Option<&Labels>) -> Result<i64, Box<dyn std::error::Error>> {
+        match (self.num_classes, labels) {
+            (Some(n), Some(labels)) if n != labels.len() as i64 => Err(format!(
+                "model {} has num_classes {} but {} labels",
+                self.name,
+                n,
+                labels.len()
+            )
+            .into()),
+            (Some(n), _) => Ok(n),
+            (None, Some(labels)) => Ok(labels.len() as i64),
+            (None, None) => Ok(IMAGENET_CLASS_COUNT),
+        }
+    }
+
+    /// The manifest preprocessing, or the ImageNet defaults at 224x224 (299x299 for inception).
+    pub fn preprocess(&self) -> PreprocessConfig {
+        match &self.preprocess {
+            Some(preprocess) => preprocess.clone(),
+            None => match self.architecture() {
+                Ok(Architecture::InceptionV3) => PreprocessConfig::square(299),
+                _ => PreprocessConfig::default(),
+            },
+        }
+    }
+
+    /// The manifest architecture, or the one matching the model name.
+    pub fn architecture(&self) -> Result<Architecture, Box<dyn std::error::Error>> {
+        match self.architecture {
+            Some(architecture) => Ok(architecture),
+            None => Architecture::from_model_name(&self.name).ok_or_else(|| {
+                format!(
+                    "cannot infer the architecture of model {}, set \"architecture\" in the manifest",
+                    self.name
+                )
+                .into()
+            }),
+        }
+    }
+}
+
+/// `manifest.json`:
+/// `{"default": "resnet34", "models": [{"name": "resnet34", "path": "model/resnet34.ot", "architecture": "resnet34", "labels": "model/imagenet.txt"}]}`
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    #[serde(default)]
+    pub default: Option<String>,
+    pub models: Vec<ModelSpec>,
+}
+
+/// Name of the model served by default among `names`: `default`, otherwise `resnet34` if
+/// listed, otherwise the first by name. Shared by the manifest and the registry so they always
+/// agree.
+fn default_model_name(default: Option<&str>, names: &[&str]) -> Option<String> {
+    match default {
+        Some(name) => Some(name.to_string()),
+        None if names.contains(&"resnet34") => Some("resnet34".to_string()),
+        None => names.iter().min().map(|name| name.to_string()),
+    }
+}
+
+impl Manifest {
+    /// Name of the model served by default, chosen like [`ModelRegistry::from_models`] does.
+    pub fn default_name(&self) -> Option<String> {
+        let names: Vec<&str> = self.models.iter().map(|m| m.name.as_str()).collect();
+        default_model_name(self.default.as_deref(), &names)
+    }
+
+    pub fn from_file(path: &str) -> Result<Manifest, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)?;
+        let manifest: Manifest = serde_json::from_str(&contents)
+            .map_err(|e| format!("invalid model manifest {}: {}", path, e))?;
+        Ok(manifest)
+    }
+
+    /// Builds a manifest from every model file in `dir`, named after the file stem: `*.ot`
+    /// var-store weights, `*.pt`/`*.ts` TorchScript modules and `*.onnx` graphs. Files of a
+    /// format this build can't load are skipped.
+    pub fn from_dir(dir: &str) -> Result<Manifest, Box<dyn std::error::Error>> {
+        let mut models = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            match ModelFormat::from_extension(extension) {
+                None => continue,
+                Some(format) if !format.is_available() => {
+                    log::info!(
+                        "func: Manifest::from_dir: skipping {:?}, built without the {} feature",
+                        path,
+                        format.feature()
+                    );
+                    continue;
+                }
+                Some(_) => {}
+            }
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                models.push(ModelSpec::new(
+                    name.to_string(),
+                    path.to_string_lossy().to_string(),
+                ));
+            }
+        }
+        models.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Manifest {
+            default: None,
+            models,
+        })
+    }
+
+    /// Resolves the models to serve: a single model `path` if set, otherwise the manifest if it
+    /// exists, otherwise every weight file in the model directory.
+    pub fn discover(config: &ModelsConfig) -> Result<Manifest, Box<dyn std::error::Error>> {
+        if let Some(path) = &config.path {
+            log::info!("func: Manifest::discover: using model path: {:?}", path);
+            let name = model_name(path);
+            return Ok(Manifest {
+                default: Some(name.clone()),
+                models: vec![ModelSpec::new(name, path.clone())],
+            });
+        }
+        let manifest = config.manifest_path();
+        if Path::new(&manifest).exists() {
+            log::info!("func: Manifest::discover: using manifest: {:?}", manifest);
+            return Manifest::from_file(&manifest);
+        }
+        log::info!(
+            "func: Manifest::discover: scanning model directory: {:?}",
+            config.dir
+        );
+        Manifest::from_dir(&config.dir)
+    }
+}
+
+/// Model name derived from a weight file path, e.g. `model/resnet34.ot` -> `resnet34`.
+pub fn model_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(path)
+        .to_string()
+}
+
+/// A served model. Reloading swaps `model` while requests keep their `Arc` to the old one.
+pub struct RegisteredModel {
+    pub spec: ModelSpec,
+    model: RwLock<Arc<Model>>,
+    reloading: AtomicBool,
+}
+
+impl RegisteredModel {
+    fn new(spec: ModelSpec, model: Model) -> RegisteredModel {
+        RegisteredModel {
+            spec,
+            model: RwLock::new(Arc::new(model)),
+            reloading: AtomicBool::new(false),
+        }
+    }
+
+    pub fn model(&self) -> Arc<Model> {
+        self.model.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The model's health, `loading` while it is reloaded.
+    pub fn health(&self) -> ModelHealthReport {
+        let mut report = self.model().health().report(&self.spec.name);
+        if self.reloading.load(Ordering::SeqCst) {
+            report.status = ModelStatus::Loading;
+            report.ready = false;
+        }
+        report
+    }
+}
+
+/// Summary of a registered model returned by `GET /models`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ModelInfo {
+    pub name: String,
+    pub path: String,
+    pub format: ModelFormat,
+    pub architecture: Option<Architecture>,
+    pub num_classes: usize,
+    pub labels: Option<String>,
+    pub default: bool,
+    pub status: ModelStatus,
+    pub batching: BatchInfo,
+    pub preprocess: PreprocessConfig,
+}
+
+pub struct ModelRegistry {
+    models: BTreeMap<String, RegisteredModel>,
+    default: String,
+    batching: BatchConfig,
+    health: HealthConfig,
+    shutting_down: AtomicBool,
+}
+
+/// Runs `model` on the self check image and records the outcome in its health.
+fn self_check(name: &str, model: &Model, image_file: &str) -> Result<(), String> {
+    match self_check_predict(model, image_file) {
+        Ok(_) => {
+            model.health().self_check_passed();
+            Ok(())
+        }
+        Err(e) => {
+            let error = format!("self check of model {} failed: {}", name, e);
+            log::error!("func: self_check: {}", error);
+            model.health().self_check_failed(error.clone());
+            Err(error)
+        }
+    }
+}
+
+impl ModelRegistry {
+    /// Loads every model in the manifest. Any model failing to load fails the whole registry.
+    /// The models aren't ready until `startup_check` ran. `batching` applies to every model,
+    /// except for the settings its manifest `"batch"` overrides.
+    pub fn load(
+        manifest: Manifest,
+        batching: &BatchConfig,
+        health: &HealthConfig,
+    ) -> Result<ModelRegistry, Box<dyn std::error::Error>> {
+        if manifest.models.is_empty() {
+            return Err("no models found to serve".into());
+        }
+        let mut models: Vec<(ModelSpec, Model)> = Vec::new();
+        for spec in manifest.models {
+            if models.iter().any(|(loaded, _)| loaded.name == spec.name) {
+                return Err(format!("duplicate model name: {}", spec.name).into());
+            }
+            let model = Model::load(&spec, batching, health).map_err(|e| {
+                format!("failed to load model {} ({}): {}", spec.name, spec.path, e)
+            })?;
+            models.push((spec, model));
+        }
+        ModelRegistry::from_models(models, manifest.default, batching, health)
+    }
+
+    /// Serves models that are already built, e.g. with a custom [`ModelBackend`]. The default
+    /// model is `default`, otherwise `resnet34` if served, otherwise the first by name. Models
+    /// are reloaded from their spec with `batching`.
+    ///
+    /// [`ModelBackend`]: crate::backend::ModelBackend
+    pub fn from_models(
+        loaded: Vec<(ModelSpec, Model)>,
+        default: Option<String>,
+        batching: &BatchConfig,
+        health: &HealthConfig,
+    ) -> Result<ModelRegistry, Box<dyn std::error::Error>> {
+        if loaded.is_empty() {
+            return Err("no models found to serve".into());
+        }
+        let mut models = BTreeMap::new();
+        for (spec, model) in loaded {
+            if models.contains_key(&spec.name) {
+                return Err(format!("duplicate model name: {}", spec.name).into());
+            }
+            models.insert(spec.name.clone(), RegisteredModel::new(spec, model));
+        }
+        let names: Vec<&str> = models.keys().map(String::as_str).collect();
+        let default =
+            default_model_name(default.as_deref(), &names).ok_or("no models found to serve")?;
+        if !models.contains_key(&default) {
+            return Err(format!("default model not found: {}", default).into());
+        }
+        log::info!(
+            "func: ModelRegistry::from_models: serving {} model(s), default: {:?}",
+            models.len(),
+            default
+        );
+        Ok(ModelRegistry {
+            models,
+            default,
+            batching: *batching,
+            health: *health,
+            shutting_down: AtomicBool::new(false),
+        })
+    }
+
+    /// Runs the self check of every model, or with `startup_self_check` off marks them ready.
+    pub fn startup_check(&self, image_file: &str) {
+        for (name, registered) in &self.models {
+            let model = registered.model();
+            if self.health.startup_self_check {
+                let _ = self_check(name, &model, image_file);
+            } else {
+                model.health().self_check_passed();
+            }
+        }
+    }
+
+    /// Runs the self check again for models that failed it or keep failing at inference, so
+    /// they become ready without a restart once they work again, even without traffic. Returns
+    /// whether any still fails.
+    pub fn recheck_failed(&self, image_file: &str) -> bool {
+        let mut failed = false;
+        for (name, registered) in &self.models {
+            let model = registered.model();
+            let status = model.health().status();
+            if status == ModelStatus::SelfCheckFailed || status == ModelStatus::Failing {
+                log::info!("func: recheck_failed: model: {}", name);
+                failed |= self_check(name, &model, image_file).is_err();
+            }
+        }
+        failed
+    }
+
+    /// Runs `startup_check`, then `recheck_failed` until the server shuts down, waiting twice as
+    /// long after each recheck that still fails. Blocks, so run it on its own thread.
+    pub fn check_in_background(&self, image_file: &str) {
+        self.startup_check(image_file);
+        let mut wait = RECHECK_MIN_WAIT;
+        while !self.is_shutting_down() {
+            std::thread::sleep(wait);
+            wait = if self.recheck_failed(image_file) {
+                (wait * 2).min(RECHECK_MAX_WAIT)
+            } else {
+                RECHECK_MIN_WAIT
+            };
+        }
+    }
+
+    /// Loads the model `name` again from its files and swaps it in once it passed the self check.
+    /// The old model keeps serving meanwhile, but the model isn't ready until the swap.
+    pub fn reload(&self, name: &str, image_file: &str) -> Result<(), ReloadError> {
+        let registered = self
+            .models
+            .get(name)
+            .ok_or_else(|| ReloadError::NotFound(name.to_string()))?;
+        if registered.reloading.swap(true, Ordering::SeqCst) {
+            return Err(ReloadError::InProgress(name.to_string()));
+        }
+        log::info!("func: ModelRegistry::reload: reloading model: {}", name);
+        let result = Model::load(&registered.spec, &self.batching, &self.health)
+            .map_err(|e| {
+                format!(
+                    "failed to load model {} ({}): {}",
+                    name, registered.spec.path, e
+                )
+            })
+            .and_then(|model| {
+                if self.health.startup_self_check {
+                    self_check(name, &model, image_file)?;
+                } else {
+                    model.health().self_check_passed();
+                }
+                Ok(model)
+            });
+        let result = match result {
+            Ok(model) => {
+                *registered.model.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(model);
+                log::info!("func: ModelRegistry::reload: reloaded model: {}", name);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("func: ModelRegistry::reload: {}", e);
+                Err(ReloadError::Failed(e))
+            }
+        };
+        registered.reloading.store(false, Ordering::SeqCst);
+        result
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Model>> {
+        self.models.get(name).map(|m| m.model())
+    }
+
+    /// The model used by `/predict` and the self check routes.
+    pub fn default_model(&self) -> Arc<Model> {
+        self.models[&self.default].model()
+    }
+
+    pub fn default_name(&self) -> &str {
+        &self.default
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.models.keys().cloned().collect()
+    }
+
+    /// Health of every model, by name.
+    pub fn health(&self) -> Vec<ModelHealthReport> {
+        self.models.values().map(|m| m.health()).collect()
+    }
+
+    /// Whether every model is ready to serve, never once the server is shutting down.
+    pub fn is_ready(&self) -> bool {
+        !self.is_shutting_down() && self.models.values().all(|m| m.health().ready)
+    }
+
+    /// Whether the model `name` is ready to serve, `None` if there is no such model.
+    pub fn model_ready(&self, name: &str) -> Option<bool> {
+        self.models
+            .get(name)
+            .map(|m| !self.is_shutting_down() && m.health().ready)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Marks the server as shutting down, so `/readyz` fails and load balancers stop sending
+    /// requests before the listeners close.
+    pub fn begin_shutdown(&self) {
+        if !self.shutting_down.swap(true, Ordering::SeqCst) {
+            log::info!("func: ModelRegistry::begin_shutdown: not ready anymore");
+        }
+    }
+
+    /// Stops the batching queues of every model and waits for the queued images until
+    /// `deadline`. Returns whether every queue was drained in time.
+    pub fn drain(&self, deadline: Instant) -> bool {
+        self.begin_shutdown();
+        let mut drained = true;
+        for (name, registered) in &self.models {
+            if registered.model().shutdown(deadline) {
+                log::info!("func: ModelRegistry::drain: drained model: {}", name);
+            } else {
+                log::warn!(
+                    "func: ModelRegistry::drain: model {} still busy at the deadline",
+                    name
+                );
+                drained = false;
+            }
+        }
+        drained
+    }
+
+    pub fn info(&self) -> Vec<ModelInfo> {
+        self.models
+            .values()
+            .map(|m| {
+                let model = m.model();
+                ModelInfo {
+                    name: m.spec.name.clone(),
+                    path: m.spec.path.clone(),
+                    format: model.format(),
+                    architecture: match model.format() {
+                        ModelFormat::VarStore => m.spec.architecture().ok(),
+                        ModelFormat::TorchScript | ModelFormat::Onnx => None,
+                    },
+                    num_classes: model.labels().len(),
+                    labels: m.spec.labels.clone(),
+                    default: m.spec.name == self.default,
+                    status: m.health().status,
+                    batching: model.batch_info(),
+                    preprocess: model.preprocess().clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Why `ModelRegistry::reload` didn't swap in a new model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReloadError {
+    NotFound(String),
+    InProgress(String),
+    Failed(String),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReloadError::NotFound(name) => write!(f, "Model not found: {}", name),
+            ReloadError::InProgress(name) => {
+                write!(f, "Model {} is already being reloaded", name)
+            }
+            ReloadError::Failed(e) => write!(f, "Reload failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {}
@@ -1,13 +1,31 @@
 use actix_multipart::Multipart;
+use actix_web::http::header::ContentType;
 use actix_web::post;
-use actix_web::{get, Error, HttpResponse, Result};
+use actix_web::{get, web, HttpMessage, HttpRequest, HttpResponse, Result};
+use serde::Serialize;
 use serde_json::json;
-use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::Instrument;
 
+use crate::config::{Config, Features};
+use crate::error::ApiError;
+use crate::fetch::{self, FetchError};
+use crate::health::ModelStatus;
+use crate::input::{self, JsonImages, JsonPredictRequest};
 use crate::logic::files;
-use crate::logic::predict_image;
-use crate::logic::self_check_predict;
+use crate::logic::predict_bytes;
+use crate::logic::predict_files;
+#[cfg(feature = "torch")]
 use crate::logic::tensor_device_cpu;
+use crate::logic::Model;
+use crate::logic::PredictParams;
+use crate::logic::Ranking;
+use crate::metrics::{metrics, Stage};
+use crate::registry::{ModelRegistry, ReloadError};
+use crate::tfserving;
+use crate::upload::{UploadError, UploadLimits};
+use crate::v2;
 
 #[get("/")]
 pub async fn index() -> HttpResponse {
@@ -16,107 +34,496 @@ pub async fn index() -> HttpResponse {
     HttpResponse::Ok().content_type("text/plain").body(message)
 }
 
+/// Reports malformed query strings, e.g. `?k=abc`, in the API error shape instead of actix's
+/// plain text response.
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default()
+        .error_handler(|e, _req| ApiError::InvalidParams(e.to_string()).into())
+}
+
+/// Registers the routes, leaving out those switched off in `features`.
+pub fn services(cfg: &mut web::ServiceConfig, features: &Features) {
+    cfg.service(index)
+        .service(healthz)
+        .service(readyz)
+        .service(predict)
+        .service(list_models)
+        .service(predict_model);
+    if features.batch_routes {
+        cfg.service(predict_batch).service(predict_model_batch);
+    }
+    if features.metrics_route {
+        cfg.service(prometheus_metrics);
+    }
+    if features.v2_routes {
+        v2::services(cfg);
+    }
+    if features.tf_serving_routes {
+        tfserving::services(cfg);
+    }
+    if features.reload_route {
+        cfg.service(reload_model);
+    }
+    if features.check_routes {
+        cfg.service(check_image_prediction)
+            .service(check_image_upload);
+        #[cfg(feature = "torch")]
+        cfg.service(check_pytorch_cpu);
+    }
+}
+
 #[post("/predict")]
-pub async fn predict(payload: Multipart) -> Result<HttpResponse, Error> {
+pub async fn predict(
+    registry: web::Data<ModelRegistry>,
+    config: web::Data<Config>,
+    params: web::Query<PredictParams>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, ApiError> {
     //log starting upload and include route and function name
     log::info!("route: /predict function: predict()");
-    // create the path if it doesn't exist
-    let temp_dir = Path::new("./tmp/");
-    if !temp_dir.exists() {
-        log::info!("Creating temp directory: {:?}", temp_dir);
-        std::fs::create_dir_all(temp_dir)?;
+    predict_request(
+        &registry.default_model(),
+        &params,
+        req,
+        payload,
+        &config,
+        "/predict",
+    )
+    .await
+}
+
+#[post("/predict/batch")]
+pub async fn predict_batch(
+    registry: web::Data<ModelRegistry>,
+    config: web::Data<Config>,
+    params: web::Query<PredictParams>,
+    payload: Multipart,
+) -> Result<HttpResponse, ApiError> {
+    log::info!("route: /predict/batch function: predict_batch()");
+    predict_upload_batch(
+        &registry.default_model(),
+        &params,
+        payload,
+        &config,
+        "/predict/batch",
+    )
+    .await
+}
+
+/// Liveness: the process is up and answering requests.
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "success", "result": { "live": true } }))
+}
+
+/// Readiness: `200` once every model loaded and passed its self check, `503` with the
+/// per-model status while any is loading, reloading, failed its self check or keeps failing
+/// at inference.
+#[get("/readyz")]
+pub async fn readyz(registry: web::Data<ModelRegistry>) -> HttpResponse {
+    let models = registry.health();
+    if registry.is_ready() {
+        return HttpResponse::Ok().json(json!({
+            "status": "success",
+            "result": { "ready": true, "models": models },
+        }));
     }
-    // save the file to the temp directory
-    let file_path = match files::save_file(payload, "/tmp/image.jpg".to_string()).await {
-        Ok(path) => path,
-        Err(e) => {
-            let error_message = format!("File upload failed with error: {:?}", e);
+    let message = if registry.is_shutting_down() {
+        "Server is shutting down".to_string()
+    } else {
+        let not_ready: Vec<String> = models
+            .iter()
+            .filter(|m| !m.ready)
+            .map(|m| m.name.clone())
+            .collect();
+        format!("Models not ready: {}", not_ready.join(", "))
+    };
+    log::warn!("route: /readyz function: readyz() {}", message);
+    HttpResponse::ServiceUnavailable().json(json!({
+        "status": "error",
+        "code": "not_ready",
+        "message": message,
+        "result": { "ready": false, "models": models },
+    }))
+}
+
+/// Prometheus metrics in the text exposition format.
+#[get("/metrics")]
+pub async fn prometheus_metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics().render())
+}
+
+#[post("/models/{name}/reload")]
+pub async fn reload_model(
+    registry: web::Data<ModelRegistry>,
+    config: web::Data<Config>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let name = name.into_inner();
+    let route = format!("/models/{}/reload", name);
+    log::info!("route: {} function: reload_model()", route);
+    let image_file = config.models.self_check_image.clone();
+    let reloaded = {
+        let registry = registry.clone();
+        let name = name.clone();
+        web::block(move || registry.reload(&name, &image_file)).await
+    };
+    reloaded
+        .map_err(|e| ApiError::Reload(ReloadError::Failed(e.to_string())))
+        .and_then(|reloaded| reloaded.map_err(ApiError::from))
+        .map_err(|error| {
+            log::error!("Route: {}, Function: reload, Error: {}", route, error);
+            error
+        })?;
+    let health = registry.health().into_iter().find(|m| m.name == name);
+    Ok(HttpResponse::Ok().json(json!({ "status": "success", "result": health })))
+}
+
+#[get("/models")]
+pub async fn list_models(registry: web::Data<ModelRegistry>) -> HttpResponse {
+    log::info!("route: /models function: list_models()");
+    HttpResponse::Ok().json(json!({ "status": "success", "result": registry.info() }))
+}
+
+#[post("/models/{name}/predict")]
+pub async fn predict_model(
+    registry: web::Data<ModelRegistry>,
+    config: web::Data<Config>,
+    name: web::Path<String>,
+    params: web::Query<PredictParams>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    let route = format!("/models/{}/predict", name);
+    log::info!("route: {} function: predict_model()", route);
+    let model = find_model(&registry, &name, &route)?;
+    predict_request(&model, &params, req, payload, &config, &route).await
+}
+
+#[post("/models/{name}/predict/batch")]
+pub async fn predict_model_batch(
+    registry: web::Data<ModelRegistry>,
+    config: web::Data<Config>,
+    name: web::Path<String>,
+    params: web::Query<PredictParams>,
+    payload: Multipart,
+) -> Result<HttpResponse, ApiError> {
+    let route = format!("/models/{}/predict/batch", name);
+    log::info!("route: {} function: predict_model_batch()", route);
+    let model = find_model(&registry, &name, &route)?;
+    predict_upload_batch(&model, &params, payload, &config, &route).await
+}
+
+fn find_model(registry: &ModelRegistry, name: &str, route: &str) -> Result<Arc<Model>, ApiError> {
+    registry.get(name).ok_or_else(|| {
+        let error = ApiError::ModelNotFound(name.to_string());
+        log::error!("Route: {}, Function: find_model, Error: {}", route, error);
+        error
+    })
+}
+
+/// Runs every image field of a multipart upload through `model`, one result per field.
+async fn predict_upload_batch(
+    model: &Model,
+    params: &PredictParams,
+    payload: Multipart,
+    config: &Config,
+    route: &str,
+) -> Result<HttpResponse, ApiError> {
+    let ranking = resolve_params(params, config, route)?;
+    let limits = &config.limits;
+    let started = Instant::now();
+    let uploaded = files::read_files(payload, limits.max_bytes)
+        .instrument(Stage::Upload.span(model.name()))
+        .await
+        .map_err(|e| {
+            log::error!("Route: {}, Function: read_files, Error: {}", route, e);
+            ApiError::from(e)
+        })?;
+    metrics().observe(Stage::Upload, model.name(), started);
+    let items = predict_files(model, uploaded, ranking, limits).await;
+    log::info!(
+        "Route: {}, Function: predict_files, Results: {:?}",
+        route,
+        items
+    );
+    Ok(success(model, items))
+}
+
+/// Applies the `[predict]` defaults, rejecting out of range `k`/`min_confidence` before the
+/// upload is read.
+fn resolve_params(
+    params: &PredictParams,
+    config: &Config,
+    route: &str,
+) -> Result<Ranking, ApiError> {
+    params.resolve(&config.predict).map_err(|error_message| {
+        log::error!(
+            "Route: {}, Function: resolve, Error: {}",
+            route,
+            error_message
+        );
+        ApiError::InvalidParams(error_message)
+    })
+}
+
+/// Runs the image(s) of a predict request through `model`, read according to the request's
+/// content type. Content types of disabled input features are unsupported.
+async fn predict_request(
+    model: &Model,
+    params: &PredictParams,
+    req: HttpRequest,
+    payload: web::Payload,
+    config: &Config,
+    route: &str,
+) -> Result<HttpResponse, ApiError> {
+    let mime = req.mime_type().ok().flatten();
+    let essence = mime.as_ref().map(|m| m.essence_str()).unwrap_or_default();
+    log::info!(
+        "Route: {}, Function: predict_request, Content-Type: {:?}",
+        route,
+        essence
+    );
+    match essence {
+        "multipart/form-data" => {
+            let multipart = Multipart::new(req.headers(), payload);
+            predict_upload(model, params, multipart, config, route).await
+        }
+        "application/json" if config.features.json_input => {
+            predict_json(model, params, payload, config, route).await
+        }
+        "image/jpeg" | "image/png" | "image/webp" if config.features.raw_input => {
+            predict_raw(model, params, payload, config, route).await
+        }
+        _ => {
+            let content_type = if essence.is_empty() { "none" } else { essence };
+            let error = ApiError::UnsupportedContentType(content_type.to_string());
             log::error!(
-                "Route: /predict, Function: predict_image, Error: {}",
-                error_message
+                "Route: {}, Function: predict_request, Error: {}",
+                route,
+                error
             );
-            return Ok(
-                HttpResponse::Ok().json(json!({ "status": "error", "message": error_message }))
+            Err(error)
+        }
+    }
+}
+
+/// Runs a raw image body, e.g. sent with `curl --data-binary`, through `model`. The format is
+/// detected from the bytes like for uploads; options come from the query string.
+async fn predict_raw(
+    model: &Model,
+    params: &PredictParams,
+    payload: web::Payload,
+    config: &Config,
+    route: &str,
+) -> Result<HttpResponse, ApiError> {
+    let ranking = resolve_params(params, config, route)?;
+    let limits = &config.limits;
+    let started = Instant::now();
+    let body = input::read_body(payload, limits.max_bytes)
+        .instrument(Stage::Upload.span(model.name()))
+        .await
+        .map_err(|e| {
+            log::error!("Route: {}, Function: read_body, Error: {}", route, e);
+            ApiError::from(e)
+        })?;
+    metrics().observe(Stage::Upload, model.name(), started);
+    predict_image_bytes(model, &body, ranking, limits, route).await
+}
+
+/// Runs the base64 image(s) of a JSON body through `model`. A single `image_b64` answers like a
+/// multipart upload, a list of `images` like `/predict/batch`.
+async fn predict_json(
+    model: &Model,
+    params: &PredictParams,
+    payload: web::Payload,
+    config: &Config,
+    route: &str,
+) -> Result<HttpResponse, ApiError> {
+    let limits = &config.limits;
+    let started = Instant::now();
+    let body = input::read_body(payload, input::json_body_limit(limits.max_bytes))
+        .instrument(Stage::Upload.span(model.name()))
+        .await
+        .map_err(|e| {
+            log::error!("Route: {}, Function: read_body, Error: {}", route, e);
+            ApiError::from(e)
+        })?;
+    metrics().observe(Stage::Upload, model.name(), started);
+    let request: JsonPredictRequest = serde_json::from_slice(&body).map_err(|e| {
+        log::error!("Route: {}, Function: predict_json, Error: {}", route, e);
+        ApiError::from(UploadError::InvalidBody(e.to_string()))
+    })?;
+    let ranking = resolve_params(&request.params(params), config, route)?;
+    let images = request.images().map_err(|e| {
+        log::error!("Route: {}, Function: predict_json, Error: {}", route, e);
+        ApiError::from(UploadError::InvalidBody(e))
+    })?;
+    match images {
+        JsonImages::Single(bytes) => {
+            predict_image_bytes(model, &bytes, ranking, limits, route).await
+        }
+        JsonImages::Url(_) if !config.features.url_input => {
+            let error = ApiError::FeatureDisabled("url_input");
+            log::error!("Route: {}, Function: predict_json, Error: {}", route, error);
+            Err(error)
+        }
+        JsonImages::Url(url) => {
+            let fetch_config = config.fetch.clone();
+            let bytes = web::block(move || fetch::fetch(&url, &fetch_config))
+                .await
+                .map_err(|e| FetchError::Failed(e.to_string()))
+                .and_then(|fetched| fetched)
+                .map_err(|e| {
+                    log::error!("Route: {}, Function: fetch, Error: {}", route, e);
+                    ApiError::from(e)
+                })?;
+            predict_image_bytes(model, &bytes, ranking, limits, route).await
+        }
+        JsonImages::List(images) => {
+            let uploaded = images
+                .into_iter()
+                .enumerate()
+                .map(|(i, bytes)| files::UploadedFile {
+                    field: i.to_string(),
+                    filename: None,
+                    bytes,
+                })
+                .collect();
+            let items = predict_files(model, uploaded, ranking, limits).await;
+            log::info!(
+                "Route: {}, Function: predict_files, Results: {:?}",
+                route,
+                items
             );
+            Ok(success(model, items))
         }
-    };
-    let cloned_file_path = file_path.clone();
-    let prediction = match predict_image(cloned_file_path).await {
-        Ok(p) => p,
-        Err(e) => {
-            let error_message = format!("Prediction failed with error: {:?}", e);
+    }
+}
+
+/// Reads the uploaded image into memory and runs it through `model`.
+async fn predict_upload(
+    model: &Model,
+    params: &PredictParams,
+    payload: Multipart,
+    config: &Config,
+    route: &str,
+) -> Result<HttpResponse, ApiError> {
+    let ranking = resolve_params(params, config, route)?;
+    let limits = &config.limits;
+    let started = Instant::now();
+    let uploaded = files::read_file(payload, limits.max_bytes)
+        .instrument(Stage::Upload.span(model.name()))
+        .await
+        .map_err(|e| {
+            log::error!("Route: {}, Function: read_file, Error: {}", route, e);
+            ApiError::from(e)
+        })?;
+    metrics().observe(Stage::Upload, model.name(), started);
+    predict_image_bytes(model, &uploaded.bytes, ranking, limits, route).await
+}
+
+/// Runs one encoded image through `model` and answers with its `Prediction`.
+async fn predict_image_bytes(
+    model: &Model,
+    bytes: &[u8],
+    ranking: Ranking,
+    limits: &UploadLimits,
+    route: &str,
+) -> Result<HttpResponse, ApiError> {
+    let prediction = predict_bytes(model, bytes, ranking, limits)
+        .await
+        .map_err(|e| {
+            let error = ApiError::from_prediction(e);
             log::error!(
-                "Route: /predict, Function: predict_image, Error: {}",
-                error_message
+                "Route: {}, Function: predict_bytes, Error: {}",
+                route,
+                error
             );
-            return Ok(HttpResponse::InternalServerError()
-                .json(json!({ "status": "error", "message": error_message })));
-        }
-    };
-    //delete file after prediction
-    std::fs::remove_file(file_path)?;
+            error
+        })?;
     log::info!(
-        "Route: /predict, Function: predict_image, Result: {:?}",
+        "Route: {}, Function: predict_bytes, Result: {:?}",
+        route,
         prediction
     );
 
-    Ok(HttpResponse::Ok().json(json!({ "status": "success", "result": prediction })))
+    Ok(success(model, prediction))
+}
+
+/// Answers with `result` in the success shape, timing the serialization as a stage of `model`.
+fn success(model: &Model, result: impl Serialize) -> HttpResponse {
+    let started = Instant::now();
+    let body = Stage::Serialize
+        .span(model.name())
+        .in_scope(|| json!({ "status": "success", "result": result }).to_string());
+    metrics().observe(Stage::Serialize, model.name(), started);
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(body)
 }
 
 #[post("/check_image_upload")]
-pub async fn check_image_upload(payload: Multipart) -> Result<HttpResponse, Error> {
+pub async fn check_image_upload(
+    config: web::Data<Config>,
+    payload: Multipart,
+) -> Result<HttpResponse, ApiError> {
     // log starting upload and include route and function name
     log::info!("route: /check_image_upload function: check_image_upload()");
 
-    // create the path if it doesn't exist
-    let temp_dir = Path::new("./tmp/");
-    if !temp_dir.exists() {
-        log::info!("Creating temp directory: {:?}", temp_dir);
-        std::fs::create_dir_all(temp_dir)?;
-    }
-
-    // use pattern matching to handle the result of saving the file
-    let result = match files::save_file(payload, "/tmp/image.jpg".to_string()).await {
-        Ok(path) => {
-            let status = "success".to_string();
-            json!({ "status": status, "filepath": path })
-        }
-        Err(e) => {
-            let status = "error".to_string();
-            let error = format!("{:?}", e);
-            json!({ "status": status, "error": error })
-        }
-    };
-    // return a successful response with the result
-    Ok(HttpResponse::Ok().json(result))
+    let uploaded = files::read_file(payload, config.limits.max_bytes)
+        .await
+        .map_err(|e| {
+            log::error!(
+                "Route: /check_image_upload, Function: read_file, Error: {}",
+                e
+            );
+            ApiError::from(e)
+        })?;
+    Ok(HttpResponse::Ok().json(json!({
+        "status": "success",
+        "field": uploaded.field,
+        "filename": uploaded.filename,
+        "size": uploaded.bytes.len()
+    })))
 }
 
+#[cfg(feature = "torch")]
 #[get("/check_pytorch_cpu")]
-async fn check_pytorch_cpu() -> Result<HttpResponse, Error> {
+async fn check_pytorch_cpu() -> HttpResponse {
     let tensor_size = tensor_device_cpu();
     let message = "PyTorch CPU: self check successful with tensor: ".to_string() + &tensor_size;
     log::info!("route: /check_pytorch_cpu function: self_check()");
-    Ok(HttpResponse::Ok().json(message))
+    HttpResponse::Ok().json(message)
 }
 
+/// Result of the last self check of the default model, as recorded by the startup check and
+/// the background rechecks. Doesn't run the model.
 #[get("/check_image_prediction")]
-pub async fn check_image_prediction() -> HttpResponse {
-    match self_check_predict() {
-        Ok(result) => {
-            log::info!(
-                "Route: /check_image_prediction, Function: self_check_image_predict, Result: {:?}",
-                result
-            );
-            HttpResponse::Ok().json(json!({"status": "success", "result": result}))
-        }
-        Err(e) => {
-            let error_message = format!("Image prediction self check failed with error: {:?}", e);
-            log::error!(
-                "Route: /check_image_prediction, Function: self_check_image_predict, Error: {}",
-                error_message
-            );
-            HttpResponse::InternalServerError()
-                .json(json!({"status": "error", "message": error_message}))
-        }
+pub async fn check_image_prediction(
+    registry: web::Data<ModelRegistry>,
+) -> Result<HttpResponse, ApiError> {
+    let name = registry.default_name();
+    let report = registry
+        .health()
+        .into_iter()
+        .find(|m| m.name == name)
+        .ok_or_else(|| ApiError::ModelNotFound(name.to_string()))?;
+    // A failing model passed its last self check, only its inferences fail.
+    if report.status == ModelStatus::Loading || report.status == ModelStatus::SelfCheckFailed {
+        let error = ApiError::SelfCheck(
+            report
+                .last_error
+                .unwrap_or_else(|| format!("model {} hasn't passed its self check yet", name)),
+        );
+        log::error!(
+            "Route: /check_image_prediction, Function: check_image_prediction, Error: {}",
+            error
+        );
+        return Err(error);
     }
+    Ok(HttpResponse::Ok().json(json!({"status": "success", "result": report})))
 }
@@ -0,0 +1,101 @@
+/*
+Graceful shutdown: on SIGTERM or SIGINT the server reports not ready, stops
+accepting connections, lets requests in flight and queued inference finish
+within `server.shutdown_timeout_secs`, then releases the models.
+ */
+use actix_web::dev::Server;
+use actix_web::rt;
+use actix_web::web;
+use futures::future::{join, select, Either};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::ServerConfig;
+use crate::registry::ModelRegistry;
+
+/// A listener besides the HTTP server serving the same models, stopped along with it.
+pub trait Listener: Send {
+    /// Stops accepting calls and waits for those in flight until `deadline`. Returns whether
+    /// they all finished.
+    fn stop(self: Box<Self>, deadline: Instant) -> bool;
+}
+
+/// Waits for SIGTERM or SIGINT and returns its name.
+pub async fn signal() -> &'static str {
+    let interrupt = Box::pin(rt::signal::ctrl_c());
+    #[cfg(unix)]
+    {
+        use rt::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            return match select(interrupt, Box::pin(terminate.recv())).await {
+                Either::Left(_) => "SIGINT",
+                Either::Right(_) => "SIGTERM",
+            };
+        }
+    }
+    let _ = interrupt.await;
+    "SIGINT"
+}
+
+/// Runs `server`, started with `disable_signals`, until it stops or `shutdown` resolves, then
+/// shuts it down:
+///
+/// 1. `/readyz` fails, while requests are still accepted for `shutdown_delay_secs` so load
+///    balancers can notice.
+/// 2. The HTTP server and `listeners` close and requests in flight get until
+///    `shutdown_timeout_secs` to finish.
+/// 3. The batching queues are drained within the same deadline.
+/// 4. The models are released.
+pub async fn serve(
+    server: Server,
+    listeners: Vec<Box<dyn Listener>>,
+    registry: web::Data<ModelRegistry>,
+    config: &ServerConfig,
+    shutdown: impl std::future::Future<Output = &'static str>,
+) -> std::io::Result<()> {
+    let handle = server.handle();
+    let (name, server) = match select(server, Box::pin(shutdown)).await {
+        Either::Left((result, _)) => return result,
+        Either::Right((name, server)) => (name, server),
+    };
+    log::info!("func: shutdown::serve: {} received, shutting down", name);
+    registry.begin_shutdown();
+    if config.shutdown_delay_secs > 0 {
+        rt::time::sleep(Duration::from_secs(config.shutdown_delay_secs)).await;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(config.shutdown_timeout_secs);
+    // Stops accepting connections and waits for the requests in flight, at most
+    // `shutdown_timeout_secs` as configured on the server.
+    let listeners = web::block(move || {
+        let mut stopped = true;
+        for listener in listeners {
+            stopped &= listener.stop(deadline);
+        }
+        stopped
+    });
+    let (_, listeners) = join(handle.stop(true), listeners).await;
+    server.await?;
+    log::info!("func: shutdown::serve: HTTP server stopped");
+    if !listeners.unwrap_or(false) {
+        log::warn!("func: shutdown::serve: calls still in flight at the deadline");
+    }
+
+    let drained = {
+        let registry = registry.clone();
+        web::block(move || registry.drain(deadline))
+            .await
+            .unwrap_or(false)
+    };
+    if !drained {
+        log::warn!("func: shutdown::serve: queued inference not finished at the deadline");
+    }
+    match Arc::try_unwrap(registry.into_inner()) {
+        Ok(registry) => {
+            drop(registry);
+            log::info!("func: shutdown::serve: models released");
+        }
+        Err(_) => log::warn!("func: shutdown::serve: models still in use, released at exit"),
+    }
+    Ok(())
+}
@@ -0,0 +1,495 @@
+/*
+Request-scoped tracing: every request runs in a `request` span carrying its
+request and trace ids, with child spans per prediction stage. Log lines are
+written as JSON with the fields of their spans, and finished spans can be
+exported to a file or an OTLP/HTTP collector.
+ */
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Instrument, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::{LogFormat, LoggingConfig};
+
+/// Header carrying the request id, read from requests and set on every response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+fn default_exporter() -> Exporter {
+    Exporter::None
+}
+
+fn default_trace_file() -> String {
+    "traces.jsonl".to_string()
+}
+
+fn default_otlp_endpoint() -> String {
+    "http://127.0.0.1:4318/v1/traces".to_string()
+}
+
+fn default_service_name() -> String {
+    "rtorchdist".to_string()
+}
+
+/// Where finished spans are sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Exporter {
+    None,
+    /// One JSON object per span and line, appended to `file`.
+    File,
+    /// OTLP/HTTP with JSON encoding, posted to `otlp_endpoint` in batches.
+    Otlp,
+}
+
+impl FromStr for Exporter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Exporter, String> {
+        match s {
+            "none" => Ok(Exporter::None),
+            "file" => Ok(Exporter::File),
+            "otlp" => Ok(Exporter::Otlp),
+            other => Err(format!(
+                "unknown exporter {:?}, use none, file or otlp",
+                other
+            )),
+        }
+    }
+}
+
+/// `[tracing]` settings. The defaults are `none`, `traces.jsonl`,
+/// `http://127.0.0.1:4318/v1/traces` and `rtorchdist`, overridden by `TRACE_EXPORTER`,
+/// `TRACE_FILE`, `OTLP_ENDPOINT` and `OTEL_SERVICE_NAME`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    #[serde(default = "default_exporter")]
+    pub exporter: Exporter,
+    #[serde(default = "default_trace_file")]
+    pub file: String,
+    #[serde(default = "default_otlp_endpoint")]
+    pub otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> TracingConfig {
+        TracingConfig {
+            exporter: default_exporter(),
+            file: default_trace_file(),
+            otlp_endpoint: default_otlp_endpoint(),
+            service_name: default_service_name(),
+        }
+    }
+}
+
+fn random_id(bytes: usize) -> String {
+    loop {
+        let id: u128 = rand::random();
+        // All-zero ids are invalid in W3C trace context.
+        if id != 0 {
+            return format!("{:032x}", id)[..bytes * 2].to_string();
+        }
+    }
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        && value.bytes().any(|b| b != b'0')
+}
+
+/// Ids tying together the spans and log lines of one request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub request_id: String,
+    /// 32 hex digits.
+    pub trace_id: String,
+    /// Span of the caller from `traceparent`, 16 hex digits.
+    pub parent_span_id: Option<String>,
+}
+
+impl TraceContext {
+    /// Reads the ids of a request: `X-Request-Id` if it is a short token, the trace and
+    /// parent span of a W3C `traceparent`, and fresh random ids for the rest.
+    pub fn from_headers(headers: &HeaderMap) -> TraceContext {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let parent = header("traceparent").and_then(parse_traceparent);
+        let trace_id = parent
+            .as_ref()
+            .map(|(trace_id, _)| trace_id.clone())
+            .unwrap_or_else(|| random_id(16));
+        let request_id = header(REQUEST_ID_HEADER)
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= 128
+                    && id
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+            })
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| trace_id.clone());
+        TraceContext {
+            request_id,
+            trace_id,
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+        }
+    }
+}
+
+/// `(trace id, parent span id)` of a `traceparent` header, `00-<trace>-<span>-<flags>`.
+pub fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    match parts.as_slice() {
+        // Later versions may append fields, version 00 has exactly four.
+        [version, trace_id, span_id, flags, rest @ ..]
+            if (*version == "00" && rest.is_empty() || is_hex(version, 2) && *version != "ff")
+                && is_hex(trace_id, 32)
+                && is_hex(span_id, 16)
+                && flags.len() == 2
+                && flags.bytes().all(|b| b.is_ascii_hexdigit()) =>
+        {
+            Some((trace_id.to_string(), span_id.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Middleware running each request in a `request` span and answering with its
+/// `X-Request-Id`, wrapped around the app with `from_fn(telemetry::request_context)`.
+pub async fn request_context(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let context = TraceContext::from_headers(req.headers());
+    let span = tracing::info_span!(
+        "request",
+        request_id = %context.request_id,
+        trace_id = %context.trace_id,
+        parent_span_id = context.parent_span_id.as_deref(),
+        method = %req.method(),
+        path = %req.path(),
+        status = tracing::field::Empty,
+    );
+    let mut response = next.call(req).instrument(span.clone()).await?;
+    span.record("status", response.status().as_u16());
+    if let Ok(value) = HeaderValue::from_str(&context.request_id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(response)
+}
+
+/// A finished span as written by the file exporter.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpanRecord {
+    pub name: String,
+    pub trace_id: String,
+    pub span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_span_id: Option<String>,
+    pub start_unix_nanos: u128,
+    pub end_unix_nanos: u128,
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl SpanRecord {
+    fn otlp(&self) -> serde_json::Value {
+        let attributes: Vec<serde_json::Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+            .collect();
+        let mut span = json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "name": self.name,
+            // SPAN_KIND_SERVER for requests, SPAN_KIND_INTERNAL for the stages.
+            "kind": if self.name == "request" { 2 } else { 1 },
+            "startTimeUnixNano": self.start_unix_nanos.to_string(),
+            "endTimeUnixNano": self.end_unix_nanos.to_string(),
+            "attributes": attributes,
+        });
+        if let Some(parent) = &self.parent_span_id {
+            span["parentSpanId"] = json!(parent);
+        }
+        span
+    }
+}
+
+/// OTLP/HTTP JSON request body exporting `spans`.
+pub fn otlp_body(service_name: &str, spans: &[SpanRecord]) -> serde_json::Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "rtorchdist" },
+                "spans": spans.iter().map(SpanRecord::otlp).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+#[derive(Default)]
+struct FieldVisitor(BTreeMap<String, String>);
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+/// Span data kept in the span's extensions until it closes.
+struct OpenSpan {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start: SystemTime,
+    attributes: BTreeMap<String, String>,
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+}
+
+/// Layer handing every closed span to the exporter thread. Spans take the trace id of their
+/// parent, root spans the `trace_id` field of the `request` span.
+pub struct ExportLayer {
+    sender: Mutex<mpsc::Sender<SpanRecord>>,
+}
+
+impl<S> Layer<S> for ExportLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        let mut attributes = visitor.0;
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<OpenSpan>()
+                .map(|open| (open.trace_id.clone(), open.span_id.clone()))
+        });
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (
+                attributes
+                    .remove("trace_id")
+                    .unwrap_or_else(|| random_id(16)),
+                attributes.remove("parent_span_id"),
+            ),
+        };
+        span.extensions_mut().insert(OpenSpan {
+            trace_id,
+            span_id: random_id(8),
+            parent_span_id,
+            start: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() {
+                let mut visitor = FieldVisitor::default();
+                values.record(&mut visitor);
+                open.attributes.extend(visitor.0);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let open = match span.extensions_mut().remove::<OpenSpan>() {
+            Some(open) => open,
+            None => return,
+        };
+        let record = SpanRecord {
+            name: span.name().to_string(),
+            trace_id: open.trace_id,
+            span_id: open.span_id,
+            parent_span_id: open.parent_span_id,
+            start_unix_nanos: unix_nanos(open.start),
+            end_unix_nanos: unix_nanos(SystemTime::now()),
+            attributes: open.attributes,
+        };
+        let _ = self
+            .sender
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .send(record);
+    }
+}
+
+/// Largest number of spans posted in one OTLP request.
+const OTLP_BATCH_SIZE: usize = 64;
+
+fn post_otlp(config: &TracingConfig, spans: &[SpanRecord]) {
+    let body = otlp_body(&config.service_name, spans);
+    if let Err(e) = ureq::post(&config.otlp_endpoint)
+        .timeout(Duration::from_secs(5))
+        .set("Content-Type", "application/json")
+        .send_string(&body.to_string())
+    {
+        // Logging here would produce more spans to export.
+        eprintln!(
+            "func: post_otlp: exporting {} spans to {} failed: {}",
+            spans.len(),
+            config.otlp_endpoint,
+            e
+        );
+    }
+}
+
+fn run_exporter(
+    receiver: mpsc::Receiver<SpanRecord>,
+    config: TracingConfig,
+    mut file: Option<File>,
+) {
+    let mut batch = Vec::new();
+    loop {
+        let received = receiver.recv_timeout(Duration::from_secs(1));
+        if let Ok(record) = &received {
+            match &mut file {
+                Some(file) => {
+                    if let Ok(line) = serde_json::to_string(record) {
+                        let _ = writeln!(file, "{}", line);
+                    }
+                }
+                None => batch.push(record.clone()),
+            }
+        }
+        let closed = matches!(received, Err(RecvTimeoutError::Disconnected));
+        let flush = batch.len() >= OTLP_BATCH_SIZE
+            || matches!(received, Err(RecvTimeoutError::Timeout))
+            || closed;
+        if flush && !batch.is_empty() {
+            post_otlp(&config, &batch);
+            batch.clear();
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+/// The span exporter configured in `config`, with the thread writing the spans. The thread
+/// exits once the layer is dropped, after exporting the remaining spans.
+pub fn export_layer(
+    config: &TracingConfig,
+) -> Result<Option<(ExportLayer, JoinHandle<()>)>, String> {
+    let file = match config.exporter {
+        Exporter::None => return Ok(None),
+        Exporter::File => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.file)
+                .map_err(|e| format!("cannot open trace file {}: {}", config.file, e))?,
+        ),
+        Exporter::Otlp => None,
+    };
+    let (sender, receiver) = mpsc::channel();
+    let config = config.clone();
+    let worker = thread::Builder::new()
+        .name("span-exporter".to_string())
+        .spawn(move || run_exporter(receiver, config, file))
+        .map_err(|e| e.to_string())?;
+    Ok(Some((
+        ExportLayer {
+            sender: Mutex::new(sender),
+        },
+        worker,
+    )))
+}
+
+/// Installs the global subscriber: log lines, including those of the `log` macros, as JSON
+/// or text on stderr or in `logging.file`, and spans exported as configured in `tracing`.
+pub fn init(logging: &LoggingConfig, tracing: &TracingConfig) -> Result<(), String> {
+    // The exporter thread lives as long as the process.
+    let (subscriber, _worker) = subscriber(logging, tracing)?;
+    subscriber.try_init().map_err(|e| e.to_string())
+}
+
+/// A subscriber that can be installed globally.
+pub type BoxSubscriber = Box<dyn Subscriber + Send + Sync>;
+
+/// The subscriber `init` installs and the exporter thread, which exits once the subscriber is
+/// dropped. `logging.level` only filters the log lines, spans are exported from `info` on
+/// whatever the log level is.
+pub fn subscriber(
+    logging: &LoggingConfig,
+    tracing: &TracingConfig,
+) -> Result<(BoxSubscriber, Option<JoinHandle<()>>), String> {
+    let level = LevelFilter::from_str(&logging.level).map_err(|e| e.to_string())?;
+    let writer = match &logging.file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("cannot open log file {}: {}", path, e))?;
+            tracing_subscriber::fmt::writer::BoxMakeWriter::new(Mutex::new(file))
+        }
+        None => tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stderr),
+    };
+    let fmt = tracing_subscriber::fmt::layer().with_writer(writer);
+    let fmt = match logging.format {
+        LogFormat::Json => fmt
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+        LogFormat::Text => fmt.with_ansi(logging.file.is_none()).boxed(),
+    };
+    let (export, worker) = match export_layer(tracing)? {
+        Some((layer, worker)) => (Some(layer.with_filter(LevelFilter::INFO)), Some(worker)),
+        None => (None, None),
+    };
+    let subscriber = tracing_subscriber::registry()
+        .with(fmt.with_filter(level))
+        .with(export);
+    Ok((Box::new(subscriber), worker))
+}
@@ -0,0 +1,205 @@
+/*
+Tensors the serving pipeline passes around: `tch::Tensor` when built with the
+`torch` feature, otherwise a small CPU float tensor with the few methods the
+pipeline uses, so an ONNX-only build doesn't link libtorch.
+ */
+#[cfg(feature = "torch")]
+pub use tch::{Kind, TchError as TensorError, Tensor};
+
+#[cfg(not(feature = "torch"))]
+pub use cpu::{Kind, Tensor, TensorError};
+
+#[cfg(not(feature = "torch"))]
+mod cpu {
+    use std::fmt;
+
+    /// Element type, only `Float` without libtorch.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Kind {
+        Float,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct TensorError(String);
+
+    impl fmt::Display for TensorError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl std::error::Error for TensorError {}
+
+    /// A dense row-major `f32` tensor.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Tensor {
+        data: Vec<f32>,
+        size: Vec<i64>,
+    }
+
+    fn last_dim(size: &[i64], dim: i64) -> Result<usize, TensorError> {
+        match size.last() {
+            Some(last) if dim == -1 || dim == size.len() as i64 - 1 => Ok(*last as usize),
+            _ => Err(TensorError(format!(
+                "only the last dimension is supported, got {} of {:?}",
+                dim, size
+            ))),
+        }
+    }
+
+    impl Tensor {
+        pub fn of_slice(data: &[f32]) -> Tensor {
+            Tensor {
+                data: data.to_vec(),
+                size: vec![data.len() as i64],
+            }
+        }
+
+        pub fn size(&self) -> Vec<i64> {
+            self.size.clone()
+        }
+
+        pub fn f_view(&self, size: impl AsRef<[i64]>) -> Result<Tensor, TensorError> {
+            let mut size = size.as_ref().to_vec();
+            let known: i64 = size.iter().filter(|d| **d != -1).product();
+            if let Some(infer) = size.iter().position(|d| *d == -1) {
+                if known > 0 {
+                    size[infer] = self.data.len() as i64 / known;
+                }
+            }
+            if size.iter().any(|d| *d < 0) || size.iter().product::<i64>() != self.data.len() as i64
+            {
+                return Err(TensorError(format!(
+                    "shape {:?} is invalid for {} values",
+                    size,
+                    self.data.len()
+                )));
+            }
+            Ok(Tensor {
+                data: self.data.clone(),
+                size,
+            })
+        }
+
+        pub fn view(&self, size: impl AsRef<[i64]>) -> Tensor {
+            self.f_view(size).unwrap()
+        }
+
+        pub fn unsqueeze(&self, dim: i64) -> Tensor {
+            let mut size = self.size.clone();
+            size.insert(dim as usize, 1);
+            Tensor {
+                data: self.data.clone(),
+                size,
+            }
+        }
+
+        /// Row `index` of the first dimension.
+        pub fn get(&self, index: i64) -> Tensor {
+            let size = self.size[1..].to_vec();
+            let len = size.iter().product::<i64>() as usize;
+            let start = index as usize * len;
+            Tensor {
+                data: self.data[start..start + len].to_vec(),
+                size,
+            }
+        }
+
+        pub fn f_stack(tensors: &[Tensor], dim: i64) -> Result<Tensor, TensorError> {
+            let first = tensors
+                .first()
+                .ok_or_else(|| TensorError("cannot stack no tensors".to_string()))?;
+            if dim != 0 || tensors.iter().any(|t| t.size != first.size) {
+                return Err(TensorError(
+                    "only tensors of the same size stack, along dimension 0".to_string(),
+                ));
+            }
+            let mut size = vec![tensors.len() as i64];
+            size.extend(&first.size);
+            Ok(Tensor {
+                data: tensors
+                    .iter()
+                    .flat_map(|t| t.data.iter().copied())
+                    .collect(),
+                size,
+            })
+        }
+
+        pub fn to_kind(&self, _kind: Kind) -> Tensor {
+            self.clone()
+        }
+
+        pub fn f_softmax(&self, dim: i64, _kind: Kind) -> Result<Tensor, TensorError> {
+            let len = last_dim(&self.size, dim)?;
+            let mut data = self.data.clone();
+            for row in data.chunks_mut(len.max(1)) {
+                let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let mut sum = 0.0;
+                for value in row.iter_mut() {
+                    *value = (*value - max).exp();
+                    sum += *value;
+                }
+                row.iter_mut().for_each(|value| *value /= sum);
+            }
+            Ok(Tensor {
+                data,
+                size: self.size.clone(),
+            })
+        }
+
+        pub fn softmax(&self, dim: i64, kind: Kind) -> Tensor {
+            self.f_softmax(dim, kind).unwrap()
+        }
+
+        /// The `k` largest values of the last dimension and their indices, largest first.
+        pub fn f_topk(
+            &self,
+            k: i64,
+            dim: i64,
+            _largest: bool,
+            _sorted: bool,
+        ) -> Result<(Tensor, Tensor), TensorError> {
+            let len = last_dim(&self.size, dim)?;
+            let k = (k as usize).min(len);
+            let (mut values, mut indices) = (Vec::new(), Vec::new());
+            for row in self.data.chunks(len.max(1)) {
+                let mut order: Vec<usize> = (0..row.len()).collect();
+                order.sort_by(|a, b| row[*b].total_cmp(&row[*a]));
+                for index in order.into_iter().take(k) {
+                    values.push(row[index]);
+                    indices.push(index as f32);
+                }
+            }
+            let mut size = self.size.clone();
+            *size.last_mut().unwrap() = k as i64;
+            Ok((
+                Tensor {
+                    data: values,
+                    size: size.clone(),
+                },
+                Tensor {
+                    data: indices,
+                    size,
+                },
+            ))
+        }
+    }
+
+    impl From<&Tensor> for Vec<f32> {
+        fn from(tensor: &Tensor) -> Vec<f32> {
+            tensor.data.clone()
+        }
+    }
+
+    impl From<&Tensor> for Vec<f64> {
+        fn from(tensor: &Tensor) -> Vec<f64> {
+            tensor.data.iter().map(|v| *v as f64).collect()
+        }
+    }
+
+    impl From<&Tensor> for Vec<i64> {
+        fn from(tensor: &Tensor) -> Vec<i64> {
+            tensor.data.iter().map(|v| *v as i64).collect()
+        }
+    }
+}
@@ -0,0 +1,151 @@
+/*
+TensorFlow Serving compatible predict API: `POST /v1/models/{name}:predict`
+with `{"instances": [{"b64": ...}]}` bodies, answered with
+`{"predictions": [...]}`, so clients of a TF Serving image classifier can call
+rtorchdist models unchanged.
+ */
+use actix_web::http::header::ContentType;
+use actix_web::{post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Instant;
+use tracing::Instrument;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::input::{self, decode_base64};
+use crate::logic::{rank, PredictParams, Prediction};
+use crate::metrics::{metrics, Stage};
+use crate::registry::ModelRegistry;
+use crate::tensor::TensorError;
+use crate::v2::{self, InputImages, V2Error};
+
+/// A `:predict` request body in the row format.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PredictRequest {
+    /// Accepted for existing clients, every model has a single signature.
+    #[serde(default)]
+    pub signature_name: Option<String>,
+    /// One encoded image per instance.
+    #[serde(default)]
+    pub instances: Option<Vec<Value>>,
+    /// The columnar format, which is refused.
+    #[serde(default)]
+    pub inputs: Option<Value>,
+}
+
+/// Decodes an instance, an image file as `{"b64": "..."}` or a single named input holding
+/// one, e.g. `{"image_bytes": {"b64": "..."}}`.
+pub fn instance_image(instance: &Value) -> Result<Vec<u8>, String> {
+    let object = match instance {
+        Value::Object(object) => object,
+        _ => return Err("must be a {\"b64\": ...} image object".to_string()),
+    };
+    match (object.get("b64"), object.values().next()) {
+        (Some(Value::String(data)), _) if object.len() == 1 => decode_base64(data),
+        (None, Some(named @ Value::Object(_))) if object.len() == 1 => instance_image(named),
+        _ => Err("must be a {\"b64\": ...} image object".to_string()),
+    }
+}
+
+impl PredictRequest {
+    /// The decoded image files of the instances.
+    pub fn images(&self) -> Result<Vec<Vec<u8>>, String> {
+        if self.inputs.is_some() {
+            return Err(
+                "the columnar \"inputs\" format is not supported, use \"instances\"".into(),
+            );
+        }
+        match &self.instances {
+            Some(instances) if !instances.is_empty() => instances
+                .iter()
+                .enumerate()
+                .map(|(i, instance)| {
+                    instance_image(instance).map_err(|e| format!("instances[{}]: {}", i, e))
+                })
+                .collect(),
+            _ => Err("\"instances\" must hold at least one image".to_string()),
+        }
+    }
+}
+
+/// A `:predict` answer, one prediction per instance in request order.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PredictResponse {
+    pub predictions: Vec<Prediction>,
+}
+
+/// Registers the `/v1/models/{name}:predict` route.
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(predict);
+}
+
+/// Ranks the classes of every instance like `/models/{name}/predict`, `?k=` and
+/// `?min_confidence=` included. Errors answer `{"error": "<message>"}` as TF Serving does, the
+/// same shape as the v2 routes.
+#[post("/v1/models/{name}:predict")]
+pub async fn predict(
+    registry: web::Data<ModelRegistry>,
+    config: web::Data<Config>,
+    name: web::Path<String>,
+    params: web::Query<PredictParams>,
+    payload: web::Payload,
+) -> Result<HttpResponse, V2Error> {
+    let route = "/v1/models/{name}:predict";
+    let model = registry
+        .get(&name)
+        .ok_or_else(|| V2Error::from(ApiError::ModelNotFound(name.to_string())))?;
+    let ranking = params.resolve(&config.predict).map_err(|e| {
+        log::error!("Route: {}, Function: resolve, Error: {}", route, e);
+        V2Error::bad_request(e)
+    })?;
+    let started = Instant::now();
+    let body = input::read_body(payload, input::json_body_limit(config.limits.max_bytes))
+        .instrument(Stage::Upload.span(model.name()))
+        .await
+        .map_err(|e| {
+            log::error!("Route: {}, Function: read_body, Error: {}", route, e);
+            V2Error::from(ApiError::from(e))
+        })?;
+    metrics().observe(Stage::Upload, model.name(), started);
+    let images = serde_json::from_slice::<PredictRequest>(&body)
+        .map_err(|e| format!("invalid predict request: {}", e))
+        .and_then(|request| request.images())
+        .map_err(|e| {
+            log::error!("Route: {}, Function: predict, Error: {}", route, e);
+            V2Error::bad_request(e)
+        })?;
+
+    let count = images.len();
+    let probabilities = v2::infer(&model, &InputImages::Encoded(images), &config.limits)
+        .await
+        .map_err(|error| {
+            log::error!("Route: {}, Function: infer, Error: {}", route, error);
+            V2Error::from(error)
+        })?;
+
+    let started = Instant::now();
+    let response = Stage::Serialize.span(model.name()).in_scope(|| {
+        let predictions = probabilities
+            .iter()
+            .map(|row| {
+                rank(row, ranking.k, ranking.min_confidence, model.labels()).map(Prediction::new)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, TensorError>(json!(PredictResponse { predictions }).to_string())
+    });
+    let body = response.map_err(|e| {
+        log::error!("Route: {}, Function: rank, Error: {}", route, e);
+        V2Error::from(ApiError::Inference(e.to_string()))
+    })?;
+    metrics().observe(Stage::Serialize, model.name(), started);
+    log::info!(
+        "Route: {}, Function: predict, model: {} instances: {}",
+        route,
+        model.name(),
+        count
+    );
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(body))
+}
@@ -0,0 +1,303 @@
+/*
+Upload limits and format validation: uploads are checked by their magic bytes
+and image headers before anything is decoded.
+ */
+use actix_web::http::StatusCode;
+use image::io::{Limits, Reader};
+use image::{DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Cursor;
+
+fn default_max_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_max_width() -> u32 {
+    8192
+}
+
+fn default_max_height() -> u32 {
+    8192
+}
+
+fn default_max_pixels() -> u64 {
+    40_000_000
+}
+
+fn default_max_frames() -> usize {
+    100
+}
+
+fn default_allowed_formats() -> Vec<String> {
+    ["jpeg", "png", "webp", "gif", "bmp"]
+        .iter()
+        .map(|f| f.to_string())
+        .collect()
+}
+
+/// Limits applied to every uploaded image, `[limits]` in the config. The defaults are 10 MiB,
+/// 8192x8192 pixels, 40 megapixels, 100 frames and `jpeg,png,webp,gif,bmp`, overridden by
+/// `MAX_UPLOAD_BYTES`, `MAX_IMAGE_WIDTH`/`MAX_IMAGE_HEIGHT`, `MAX_IMAGE_PIXELS`,
+/// `MAX_IMAGE_FRAMES` and `ALLOWED_IMAGE_FORMATS`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UploadLimits {
+    /// Size of the upload body, all files of a batch upload together.
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    #[serde(default = "default_max_width")]
+    pub max_width: u32,
+    #[serde(default = "default_max_height")]
+    pub max_height: u32,
+    /// Decoded `width * height`, guards against small files decoding to huge images.
+    #[serde(default = "default_max_pixels")]
+    pub max_pixels: u64,
+    /// Frames of animated GIF, PNG and WebP images.
+    #[serde(default = "default_max_frames")]
+    pub max_frames: usize,
+    #[serde(default = "default_allowed_formats")]
+    pub allowed_formats: Vec<String>,
+}
+
+impl Default for UploadLimits {
+    fn default() -> UploadLimits {
+        UploadLimits {
+            max_bytes: default_max_bytes(),
+            max_width: default_max_width(),
+            max_height: default_max_height(),
+            max_pixels: default_max_pixels(),
+            max_frames: default_max_frames(),
+            allowed_formats: default_allowed_formats(),
+        }
+    }
+}
+
+/// Why an upload was refused. Each variant has a stable `code` returned to the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadError {
+    NoFiles,
+    Multipart(String),
+    /// A request body that can't be read or parsed, e.g. malformed JSON or base64.
+    InvalidBody(String),
+    PayloadTooLarge {
+        limit: usize,
+    },
+    UnsupportedFormat(Option<String>),
+    ImageTooLarge {
+        width: u32,
+        height: u32,
+    },
+    TooManyFrames {
+        frames: usize,
+        limit: usize,
+    },
+    InvalidImage(String),
+}
+
+impl UploadError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            UploadError::NoFiles => "no_files",
+            UploadError::Multipart(_) => "invalid_multipart",
+            UploadError::InvalidBody(_) => "invalid_body",
+            UploadError::PayloadTooLarge { .. } => "payload_too_large",
+            UploadError::UnsupportedFormat(_) => "unsupported_media_type",
+            UploadError::ImageTooLarge { .. } => "image_too_large",
+            UploadError::TooManyFrames { .. } => "too_many_frames",
+            UploadError::InvalidImage(_) => "invalid_image",
+        }
+    }
+
+    /// 413 for limits, 415 for refused formats and 400 for anything else.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            UploadError::PayloadTooLarge { .. }
+            | UploadError::ImageTooLarge { .. }
+            | UploadError::TooManyFrames { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            UploadError::UnsupportedFormat(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            UploadError::NoFiles
+            | UploadError::Multipart(_)
+            | UploadError::InvalidBody(_)
+            | UploadError::InvalidImage(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadError::NoFiles => write!(f, "No files uploaded"),
+            UploadError::Multipart(e) => write!(f, "Invalid multipart upload: {}", e),
+            UploadError::InvalidBody(e) => write!(f, "Invalid request body: {}", e),
+            UploadError::PayloadTooLarge { limit } => {
+                write!(f, "Upload exceeds the limit of {} bytes", limit)
+            }
+            UploadError::UnsupportedFormat(Some(format)) => {
+                write!(f, "Image format {} is not allowed", format)
+            }
+            UploadError::UnsupportedFormat(None) => write!(f, "Upload is not a known image format"),
+            UploadError::ImageTooLarge { width, height } => {
+                write!(f, "Image of {}x{} pixels exceeds the limit", width, height)
+            }
+            UploadError::TooManyFrames { frames, limit } => {
+                write!(f, "Image has {} frames, the limit is {}", frames, limit)
+            }
+            UploadError::InvalidImage(e) => write!(f, "Invalid image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+/// Name of an image format as written in `allowed_formats`.
+pub fn format_name(format: ImageFormat) -> String {
+    match format {
+        ImageFormat::Jpeg => "jpeg".to_string(),
+        ImageFormat::WebP => "webp".to_string(),
+        other => format!("{:?}", other).to_lowercase(),
+    }
+}
+
+/// Checks an upload against `limits` from its magic bytes and headers, without decoding the
+/// pixels. Returns the detected format.
+pub fn check_image(bytes: &[u8], limits: &UploadLimits) -> Result<ImageFormat, UploadError> {
+    if bytes.len() > limits.max_bytes {
+        return Err(UploadError::PayloadTooLarge {
+            limit: limits.max_bytes,
+        });
+    }
+    let format = image::guess_format(bytes).map_err(|_| UploadError::UnsupportedFormat(None))?;
+    let name = format_name(format);
+    if !limits.allowed_formats.contains(&name) {
+        return Err(UploadError::UnsupportedFormat(Some(name)));
+    }
+    let (width, height) = Reader::with_format(Cursor::new(bytes), format)
+        .into_dimensions()
+        .map_err(|e| UploadError::InvalidImage(e.to_string()))?;
+    if width > limits.max_width
+        || height > limits.max_height
+        || width as u64 * height as u64 > limits.max_pixels
+    {
+        return Err(UploadError::ImageTooLarge { width, height });
+    }
+    let frames = frame_count(bytes, format);
+    if frames > limits.max_frames {
+        return Err(UploadError::TooManyFrames {
+            frames,
+            limit: limits.max_frames,
+        });
+    }
+    Ok(format)
+}
+
+/// Checks an upload and decodes it, with the decoder itself held to the same dimensions.
+pub fn decode_image(bytes: &[u8], limits: &UploadLimits) -> Result<DynamicImage, UploadError> {
+    let format = check_image(bytes, limits)?;
+    let mut reader = Reader::with_format(Cursor::new(bytes), format);
+    let mut decoder_limits = Limits::default();
+    decoder_limits.max_image_width = Some(limits.max_width);
+    decoder_limits.max_image_height = Some(limits.max_height);
+    reader.limits(decoder_limits);
+    reader
+        .decode()
+        .map_err(|e| UploadError::InvalidImage(e.to_string()))
+}
+
+/// Number of frames of an animated image, from the container structure. Formats without
+/// animation count as one frame.
+pub fn frame_count(bytes: &[u8], format: ImageFormat) -> usize {
+    match format {
+        ImageFormat::Gif => gif_frames(bytes),
+        ImageFormat::Png => png_frames(bytes),
+        ImageFormat::WebP => webp_frames(bytes),
+        _ => 1,
+    }
+}
+
+/// Counts the image descriptors of a GIF, skipping color tables and data sub-blocks.
+fn gif_frames(bytes: &[u8]) -> usize {
+    fn skip_sub_blocks(bytes: &[u8], mut pos: usize) -> usize {
+        while let Some(&len) = bytes.get(pos) {
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            pos += len as usize;
+        }
+        pos
+    }
+    fn color_table_size(flags: u8) -> usize {
+        if flags & 0x80 != 0 {
+            3 << ((flags & 0x07) + 1)
+        } else {
+            0
+        }
+    }
+
+    let mut pos = match bytes.get(10) {
+        Some(&flags) => 13 + color_table_size(flags),
+        None => return 0,
+    };
+    let mut frames = 0;
+    while let Some(&block) = bytes.get(pos) {
+        match block {
+            // Image descriptor, then an optional local color table and the LZW data.
+            0x2C => {
+                frames += 1;
+                let flags = match bytes.get(pos + 9) {
+                    Some(&flags) => flags,
+                    None => break,
+                };
+                pos += 10 + color_table_size(flags) + 1;
+                pos = skip_sub_blocks(bytes, pos);
+            }
+            // Extension: label, then data sub-blocks.
+            0x21 => pos = skip_sub_blocks(bytes, pos + 2),
+            _ => break,
+        }
+    }
+    frames
+}
+
+/// APNG frame count from the `acTL` chunk, 1 for still PNGs.
+fn png_frames(bytes: &[u8]) -> usize {
+    let mut pos = 8;
+    while pos + 8 <= bytes.len() {
+        let len = u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+            as usize;
+        let kind = &bytes[pos + 4..pos + 8];
+        if kind == b"acTL" {
+            return match bytes.get(pos + 8..pos + 12) {
+                Some(n) => u32::from_be_bytes([n[0], n[1], n[2], n[3]]) as usize,
+                None => 1,
+            };
+        }
+        // `acTL` must come before the image data.
+        if kind == b"IDAT" {
+            break;
+        }
+        pos += 12 + len;
+    }
+    1
+}
+
+/// Number of `ANMF` chunks of an animated WebP, 1 for still images.
+fn webp_frames(bytes: &[u8]) -> usize {
+    let mut pos = 12;
+    let mut frames = 0;
+    while pos + 8 <= bytes.len() {
+        let len = u32::from_le_bytes([
+            bytes[pos + 4],
+            bytes[pos + 5],
+            bytes[pos + 6],
+            bytes[pos + 7],
+        ]) as usize;
+        if &bytes[pos..pos + 4] == b"ANMF" {
+            frames += 1;
+        }
+        // Chunks are padded to an even size.
+        pos += 8 + len + (len & 1);
+    }
+    frames.max(1)
+}
@@ -0,0 +1,644 @@
+/*
+Open Inference Protocol (KServe v2) over REST: server and model health, model
+metadata and tensor inference under `/v2`, answering in the protocol's JSON
+shapes so gateways and clients can use rtorchdist like any other v2 server.
+ */
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpResponse, ResponseError};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::Instant;
+use tracing::Instrument;
+
+use crate::backend::ModelFormat;
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::input::{self, decode_base64};
+use crate::logic::{infer_bytes, infer_image, rank, Model};
+use crate::metrics::{metrics, Stage};
+use crate::preprocess::PreprocessConfig;
+use crate::registry::ModelRegistry;
+use crate::tensor::{Kind, Tensor, TensorError};
+use crate::upload::UploadLimits;
+
+/// Name of the image input of every model.
+pub const INPUT_NAME: &str = "input";
+/// Name of the class probabilities output of every model.
+pub const OUTPUT_NAME: &str = "probabilities";
+
+/// Name and shape of a model input or output, `-1` for the batch dimension.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TensorMetadata {
+    pub name: String,
+    pub datatype: String,
+    pub shape: Vec<i64>,
+}
+
+/// Response of `GET /v2/models/{name}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ModelMetadata {
+    pub name: String,
+    pub platform: String,
+    pub inputs: Vec<TensorMetadata>,
+    pub outputs: Vec<TensorMetadata>,
+}
+
+impl ModelMetadata {
+    /// Metadata of a model taking preprocessed `[N, 3, H, W]` images and answering with
+    /// `[N, num_classes]` probabilities.
+    pub fn new(
+        name: &str,
+        format: ModelFormat,
+        preprocess: &PreprocessConfig,
+        num_classes: usize,
+    ) -> ModelMetadata {
+        let platform = match format {
+            ModelFormat::VarStore => "pytorch_libtorch",
+            ModelFormat::TorchScript => "pytorch_torchscript",
+            ModelFormat::Onnx => "onnx_onnxv1",
+        };
+        ModelMetadata {
+            name: name.to_string(),
+            platform: platform.to_string(),
+            inputs: vec![TensorMetadata {
+                name: INPUT_NAME.to_string(),
+                datatype: "FP32".to_string(),
+                shape: vec![
+                    -1,
+                    3,
+                    preprocess.size.height() as i64,
+                    preprocess.size.width() as i64,
+                ],
+            }],
+            outputs: vec![TensorMetadata {
+                name: OUTPUT_NAME.to_string(),
+                datatype: "FP32".to_string(),
+                shape: vec![-1, num_classes as i64],
+            }],
+        }
+    }
+
+    pub fn of(model: &Model) -> ModelMetadata {
+        ModelMetadata::new(
+            model.name(),
+            model.format(),
+            model.preprocess(),
+            model.labels().len(),
+        )
+    }
+}
+
+/// One input tensor of an inference request, `data` flat or nested in row-major order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestInput {
+    pub name: String,
+    pub shape: Vec<i64>,
+    pub datatype: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Map<String, Value>>,
+    pub data: Value,
+}
+
+/// An output the client asks for. The `classification` parameter answers with the top
+/// classes instead of every probability.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestOutput {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Map<String, Value>>,
+}
+
+/// Body of `POST /v2/models/{name}/infer`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InferenceRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Map<String, Value>>,
+    pub inputs: Vec<RequestInput>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<RequestOutput>,
+}
+
+/// Images of an inference request.
+#[derive(Debug, PartialEq)]
+pub enum InputImages {
+    /// Preprocessed `FP32` images, `[N, 3, H, W]` row-major values.
+    Tensor { data: Vec<f32>, shape: [i64; 4] },
+    /// Encoded image files sent as base64 `BYTES`.
+    Encoded(Vec<Vec<u8>>),
+}
+
+impl InputImages {
+    pub fn len(&self) -> usize {
+        match self {
+            InputImages::Tensor { shape, .. } => shape[0] as usize,
+            InputImages::Encoded(images) => images.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// How an output is answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    /// `FP32` `[N, num_classes]` probabilities.
+    Probabilities,
+    /// `BYTES` `[N, k]` `"<probability>:<index>:<label>"` of the `k` most likely classes.
+    Classification(usize),
+}
+
+/// Layout of a valid model input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputShape {
+    /// `FP32` or `FP64` preprocessed images, `[N, 3, H, W]`.
+    Tensor([i64; 4]),
+    /// `N` encoded image files as `BYTES`.
+    Encoded(usize),
+}
+
+/// Checks the `name`, `datatype` and `shape` of an input against the model input taking
+/// preprocessed images like `preprocess`, `[N, 3, H, W]` or a single `[3, H, W]`, or encoded
+/// image files, `[N]` or `[N, 1]`. Each value takes at least a byte of the request, so N is
+/// refused when `limits.max_bytes` can't hold that many images.
+pub fn input_shape(
+    name: &str,
+    datatype: &str,
+    shape: &[i64],
+    preprocess: &PreprocessConfig,
+    limits: &UploadLimits,
+) -> Result<InputShape, String> {
+    if name != INPUT_NAME {
+        return Err(format!(
+            "unknown input {:?}, the model takes {:?}",
+            name, INPUT_NAME
+        ));
+    }
+    if shape.iter().any(|dim| *dim < 0) {
+        return Err(format!("invalid shape {:?}", shape));
+    }
+    let max_bytes = limits.max_bytes as i64;
+    let too_many = |n: i64, max: i64| {
+        format!(
+            "{} images don't fit in the {} byte request limit, at most {}",
+            n, max_bytes, max
+        )
+    };
+    match datatype {
+        "FP32" | "FP64" => {
+            let (height, width) = (
+                preprocess.size.height() as i64,
+                preprocess.size.width() as i64,
+            );
+            let max = max_bytes / height.saturating_mul(width).saturating_mul(3).max(1);
+            match shape {
+                [n, 3, h, w] if *h == height && *w == width => {
+                    if *n > max {
+                        return Err(too_many(*n, max));
+                    }
+                    Ok(InputShape::Tensor([*n, 3, height, width]))
+                }
+                [3, h, w] if *h == height && *w == width => {
+                    Ok(InputShape::Tensor([1, 3, height, width]))
+                }
+                _ => Err(format!(
+                    "shape {:?} doesn't match the model input [-1, 3, {}, {}]",
+                    shape, height, width
+                )),
+            }
+        }
+        "BYTES" => match shape {
+            [n] | [n, 1] if *n > max_bytes => Err(too_many(*n, max_bytes)),
+            [n] | [n, 1] => Ok(InputShape::Encoded(*n as usize)),
+            _ => Err(format!("BYTES shape {:?} must be [N] or [N, 1]", shape)),
+        },
+        other => Err(format!(
+            "unsupported datatype {:?}, use FP32, FP64 or BYTES",
+            other
+        )),
+    }
+}
+
+/// Checks that `shape` holds `len` values.
+pub fn check_len(shape: &[i64], len: usize) -> Result<(), String> {
+    let expected = shape
+        .iter()
+        .try_fold(1i64, |product, dim| product.checked_mul(*dim))
+        .ok_or_else(|| format!("shape {:?} holds too many values", shape))?;
+    if len as i64 != expected {
+        return Err(format!(
+            "shape {:?} needs {} values, got {}",
+            shape, expected, len
+        ));
+    }
+    Ok(())
+}
+
+/// How the output `name` is answered, `classification` being the number of top classes asked
+/// for.
+pub fn output_kind(name: &str, classification: Option<i64>) -> Result<OutputKind, String> {
+    if name != OUTPUT_NAME {
+        return Err(format!(
+            "unknown output {:?}, the model has {:?}",
+            name, OUTPUT_NAME
+        ));
+    }
+    match classification {
+        None => Ok(OutputKind::Probabilities),
+        Some(k) if k > 0 => Ok(OutputKind::Classification(k as usize)),
+        Some(k) => Err(format!(
+            "classification must be a positive integer, got {}",
+            k
+        )),
+    }
+}
+
+fn flatten<'a>(value: &'a Value, values: &mut Vec<&'a Value>) {
+    match value {
+        Value::Array(items) => items.iter().for_each(|item| flatten(item, values)),
+        value => values.push(value),
+    }
+}
+
+impl RequestInput {
+    /// Checks the input against the model and reads its images: `FP32` or `FP64` values of
+    /// preprocessed images shaped like `preprocess`, or base64 encoded image files as `BYTES`.
+    pub fn images(
+        &self,
+        preprocess: &PreprocessConfig,
+        limits: &UploadLimits,
+    ) -> Result<InputImages, String> {
+        let shape = input_shape(&self.name, &self.datatype, &self.shape, preprocess, limits)?;
+        let mut values = Vec::new();
+        flatten(&self.data, &mut values);
+        check_len(&self.shape, values.len())?;
+        match shape {
+            InputShape::Tensor(shape) => {
+                let data = values
+                    .iter()
+                    .map(|value| value.as_f64().map(|v| v as f32))
+                    .collect::<Option<Vec<f32>>>()
+                    .ok_or_else(|| format!("{} data must be numbers", self.datatype))?;
+                Ok(InputImages::Tensor { data, shape })
+            }
+            InputShape::Encoded(_) => values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    value
+                        .as_str()
+                        .ok_or_else(|| "BYTES data must be base64 strings".to_string())
+                        .and_then(decode_base64)
+                        .map_err(|e| format!("data[{}]: {}", i, e))
+                })
+                .collect::<Result<_, _>>()
+                .map(InputImages::Encoded),
+        }
+    }
+}
+
+impl InferenceRequest {
+    /// The images of the single input.
+    pub fn images(
+        &self,
+        preprocess: &PreprocessConfig,
+        limits: &UploadLimits,
+    ) -> Result<InputImages, String> {
+        match self.inputs.as_slice() {
+            [input] => input.images(preprocess, limits),
+            inputs => Err(format!("expected 1 input, got {}", inputs.len())),
+        }
+    }
+
+    /// The outputs asked for, the probabilities when none are named.
+    pub fn outputs(&self) -> Result<Vec<OutputKind>, String> {
+        if self.outputs.is_empty() {
+            return Ok(vec![OutputKind::Probabilities]);
+        }
+        self.outputs
+            .iter()
+            .map(|output| {
+                let parameters = output.parameters.as_ref();
+                if parameters.and_then(|p| p.get("binary_data")) == Some(&Value::Bool(true)) {
+                    return Err("binary data outputs are not supported".to_string());
+                }
+                // Anything but an integer is refused like a zero.
+                let classification = parameters
+                    .and_then(|p| p.get("classification"))
+                    .map(|k| k.as_i64().unwrap_or(0));
+                output_kind(&output.name, classification)
+            })
+            .collect()
+    }
+}
+
+/// Values of an output tensor.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputData {
+    Fp32(Vec<f32>),
+    Bytes(Vec<String>),
+}
+
+impl OutputData {
+    pub fn datatype(&self) -> &'static str {
+        match self {
+            OutputData::Fp32(_) => "FP32",
+            OutputData::Bytes(_) => "BYTES",
+        }
+    }
+}
+
+/// An output tensor, named [`OUTPUT_NAME`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    pub shape: Vec<usize>,
+    pub data: OutputData,
+}
+
+/// The `kind` output of the `[num_classes]` probabilities of each image.
+pub fn output(
+    kind: OutputKind,
+    probabilities: &[Tensor],
+    model: &Model,
+) -> Result<Output, TensorError> {
+    match kind {
+        OutputKind::Probabilities => {
+            let num_classes = model.labels().len();
+            let mut data = Vec::with_capacity(probabilities.len() * num_classes);
+            for row in probabilities {
+                data.extend(Vec::<f32>::from(&row.f_view([-1])?.to_kind(Kind::Float)));
+            }
+            Ok(Output {
+                shape: vec![probabilities.len(), num_classes],
+                data: OutputData::Fp32(data),
+            })
+        }
+        OutputKind::Classification(k) => {
+            let k = k.min(model.labels().len());
+            let mut data = Vec::with_capacity(probabilities.len() * k);
+            for row in probabilities {
+                for class in rank(row, k, 0.0, model.labels())? {
+                    data.push(format!(
+                        "{}:{}:{}",
+                        class.probability, class.index, class.label
+                    ));
+                }
+            }
+            Ok(Output {
+                shape: vec![probabilities.len(), k],
+                data: OutputData::Bytes(data),
+            })
+        }
+    }
+}
+
+/// Runs `images` through `model`, sharing forward passes in its batching queue, and returns
+/// the probabilities of each image.
+pub async fn infer(
+    model: &Model,
+    images: &InputImages,
+    limits: &UploadLimits,
+) -> Result<Vec<Tensor>, ApiError> {
+    // Errors are converted right away, so the joined futures stay `Send` for gRPC.
+    let results = match images {
+        InputImages::Tensor { data, shape } => {
+            let batch = Tensor::of_slice(data).view(*shape);
+            join_all((0..shape[0]).map(|i| {
+                let image = batch.get(i);
+                async move {
+                    infer_image(model, image)
+                        .await
+                        .map_err(ApiError::from_prediction)
+                }
+            }))
+            .await
+        }
+        InputImages::Encoded(images) => {
+            join_all(images.iter().map(|image| async move {
+                infer_bytes(model, image, limits)
+                    .await
+                    .map_err(ApiError::from_prediction)
+            }))
+            .await
+        }
+    };
+    results.into_iter().collect()
+}
+
+/// One output tensor of an inference response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResponseOutput {
+    pub name: String,
+    pub datatype: String,
+    pub shape: Vec<usize>,
+    pub data: Value,
+}
+
+impl From<Output> for ResponseOutput {
+    fn from(output: Output) -> ResponseOutput {
+        ResponseOutput {
+            name: OUTPUT_NAME.to_string(),
+            datatype: output.data.datatype().to_string(),
+            shape: output.shape,
+            data: match output.data {
+                OutputData::Fp32(data) => json!(data),
+                OutputData::Bytes(data) => json!(data),
+            },
+        }
+    }
+}
+
+/// Response of `POST /v2/models/{name}/infer`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InferenceResponse {
+    pub model_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub outputs: Vec<ResponseOutput>,
+}
+
+/// Errors answered as the protocol's `{"error": "<message>"}`, with the status code of the
+/// matching [`ApiError`].
+#[derive(Debug)]
+pub struct V2Error {
+    status: StatusCode,
+    message: String,
+}
+
+impl V2Error {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> V2Error {
+        V2Error {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> V2Error {
+        V2Error::new(StatusCode::BAD_REQUEST, message)
+    }
+}
+
+impl fmt::Display for V2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<ApiError> for V2Error {
+    fn from(error: ApiError) -> V2Error {
+        V2Error::new(error.status_code(), error.to_string())
+    }
+}
+
+impl ResponseError for V2Error {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(json!({ "error": self.message }))
+    }
+}
+
+/// Registers the `/v2` routes.
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(server_metadata)
+        .service(server_live)
+        .service(server_ready)
+        .service(model_metadata)
+        .service(model_ready)
+        .service(model_infer);
+}
+
+fn model(registry: &ModelRegistry, name: &str) -> Result<std::sync::Arc<Model>, V2Error> {
+    registry
+        .get(name)
+        .ok_or_else(|| ApiError::ModelNotFound(name.to_string()).into())
+}
+
+/// Readiness answer: `200` when ready, `400` otherwise as the protocol asks for a 4xx.
+fn readiness(ready: bool, body: Value) -> HttpResponse {
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    HttpResponse::build(status).json(body)
+}
+
+#[get("/v2")]
+pub async fn server_metadata() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "name": "rtorchdist",
+        "version": env!("CARGO_PKG_VERSION"),
+        "extensions": ["classification"],
+    }))
+}
+
+#[get("/v2/health/live")]
+pub async fn server_live() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "live": true }))
+}
+
+#[get("/v2/health/ready")]
+pub async fn server_ready(registry: web::Data<ModelRegistry>) -> HttpResponse {
+    let ready = registry.is_ready();
+    readiness(ready, json!({ "ready": ready }))
+}
+
+#[get("/v2/models/{name}")]
+pub async fn model_metadata(
+    registry: web::Data<ModelRegistry>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, V2Error> {
+    let model = model(&registry, &name)?;
+    Ok(HttpResponse::Ok().json(ModelMetadata::of(&model)))
+}
+
+#[get("/v2/models/{name}/ready")]
+pub async fn model_ready(
+    registry: web::Data<ModelRegistry>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, V2Error> {
+    let ready = registry
+        .model_ready(&name)
+        .ok_or_else(|| V2Error::from(ApiError::ModelNotFound(name.to_string())))?;
+    Ok(readiness(
+        ready,
+        json!({ "name": name.as_str(), "ready": ready }),
+    ))
+}
+
+#[post("/v2/models/{name}/infer")]
+pub async fn model_infer(
+    registry: web::Data<ModelRegistry>,
+    config: web::Data<Config>,
+    name: web::Path<String>,
+    payload: web::Payload,
+) -> Result<HttpResponse, V2Error> {
+    let route = "/v2/models/{name}/infer";
+    let model = model(&registry, &name)?;
+    let started = Instant::now();
+    let body = input::read_body(payload, input::json_body_limit(config.limits.max_bytes))
+        .instrument(Stage::Upload.span(model.name()))
+        .await
+        .map_err(|e| {
+            log::error!("Route: {}, Function: read_body, Error: {}", route, e);
+            V2Error::from(ApiError::from(e))
+        })?;
+    metrics().observe(Stage::Upload, model.name(), started);
+    let request: InferenceRequest = serde_json::from_slice(&body).map_err(|e| {
+        log::error!("Route: {}, Function: model_infer, Error: {}", route, e);
+        V2Error::bad_request(format!("invalid inference request: {}", e))
+    })?;
+    let (images, outputs) = request
+        .images(model.preprocess(), &config.limits)
+        .and_then(|images| Ok((images, request.outputs()?)))
+        .map_err(|e| {
+            log::error!("Route: {}, Function: model_infer, Error: {}", route, e);
+            V2Error::bad_request(e)
+        })?;
+    if images.is_empty() {
+        return Err(V2Error::bad_request("the input holds no images"));
+    }
+
+    let probabilities = infer(&model, &images, &config.limits)
+        .await
+        .map_err(|error| {
+            log::error!("Route: {}, Function: infer, Error: {}", route, error);
+            V2Error::from(error)
+        })?;
+
+    let started = Instant::now();
+    let response = Stage::Serialize.span(model.name()).in_scope(|| {
+        let outputs = outputs
+            .into_iter()
+            .map(|kind| output(kind, &probabilities, &model).map(ResponseOutput::from))
+            .collect::<Result<Vec<_>, _>>()?;
+        let response = InferenceResponse {
+            model_name: model.name().to_string(),
+            id: request.id.clone(),
+            outputs,
+        };
+        Ok::<_, TensorError>(json!(response).to_string())
+    });
+    let body = response.map_err(|e| {
+        log::error!("Route: {}, Function: output, Error: {}", route, e);
+        V2Error::from(ApiError::Inference(e.to_string()))
+    })?;
+    metrics().observe(Stage::Serialize, model.name(), started);
+    log::info!(
+        "Route: {}, Function: model_infer, images: {}",
+        route,
+        images.len()
+    );
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(body))
+}
@@ -0,0 +1,83 @@
+use futures::executor::block_on;
+use futures::future::join_all;
+use rtorchdist::backend::{ModelBackend, ModelFormat};
+use rtorchdist::batch::{BatchConfig, Batcher};
+use rtorchdist::tensor::Tensor;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Returns one row of constant logits per image in the batch.
+struct ConstantBackend;
+
+fn image(size: i64) -> Tensor {
+    Tensor::of_slice(&vec![0.0f32; (3 * size * size) as usize]).view([3, size, size])
+}
+
+impl ModelBackend for ConstantBackend {
+    fn forward(&self, images: &Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        let batch_size = images.size()[0];
+        Ok(Tensor::of_slice(&vec![1.0f32; batch_size as usize * 4]).view([batch_size, 4]))
+    }
+
+    fn format(&self) -> ModelFormat {
+        ModelFormat::Onnx
+    }
+}
+
+#[test]
+fn test_batcher_groups_concurrent_requests() {
+    let config = BatchConfig {
+        max_batch_size: 4,
+        max_wait_ms: 1000,
+    };
+    let batcher = Batcher::start("constant", Arc::new(ConstantBackend), config);
+    let images = (0..4).map(|_| image(8));
+    let outputs = block_on(join_all(images.map(|image| batcher.submit(image))));
+    for output in outputs {
+        let output = output.unwrap();
+        assert_eq!(output.size(), [4]);
+        assert_eq!(Vec::<f32>::from(&output), vec![0.25; 4]);
+    }
+    let info = batcher.info();
+    assert_eq!(info.images, 4);
+    assert_eq!(info.batch_sizes.get(&4), Some(&1));
+}
+
+#[test]
+fn test_batcher_splits_mixed_image_sizes() {
+    let config = BatchConfig {
+        max_batch_size: 4,
+        max_wait_ms: 1000,
+    };
+    let batcher = Batcher::start("constant", Arc::new(ConstantBackend), config);
+    let images = vec![image(8), image(16), image(8), image(16)];
+    let outputs = block_on(join_all(
+        images.into_iter().map(|image| batcher.submit(image)),
+    ));
+    assert!(outputs.iter().all(|output| output.is_ok()));
+    assert_eq!(batcher.info().batch_sizes.get(&2), Some(&2));
+}
+
+#[test]
+fn test_shutdown_drains_queued_images() {
+    let config = BatchConfig {
+        max_batch_size: 4,
+        max_wait_ms: 200,
+    };
+    let batcher = Batcher::start("constant", Arc::new(ConstantBackend), config);
+    let queued: Vec<_> = (0..2).map(|_| batcher.submit(image(8))).collect();
+    // Shuts down while the images wait for the batch to fill up.
+    let (outputs, drained) = std::thread::scope(|scope| {
+        let drained = scope.spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            batcher.shutdown(Instant::now() + Duration::from_secs(5))
+        });
+        (block_on(join_all(queued)), drained.join().unwrap())
+    });
+    assert!(drained);
+    assert!(outputs.iter().all(|output| output.is_ok()));
+    assert_eq!(batcher.info().images, 2);
+
+    let refused = block_on(batcher.submit(image(8)));
+    assert!(refused.is_err());
+}
@@ -0,0 +1,65 @@
+use clap::Parser;
+use rtorchdist::cli::{self, Cli, Command};
+use rtorchdist::config::Config;
+use rtorchdist::logic::PredictParams;
+
+#[test]
+fn test_parse_subcommands() {
+    let cli = Cli::try_parse_from(["rtorchdist"]).unwrap();
+    assert_eq!(cli.command, None);
+
+    let cli = Cli::try_parse_from(["rtorchdist", "--bind", "127.0.0.1:9000", "serve"]).unwrap();
+    assert_eq!(cli.command, Some(Command::Serve));
+    assert_eq!(cli.config.bind.as_deref(), Some("127.0.0.1:9000"));
+
+    // Config flags may follow the subcommand.
+    let cli = Cli::try_parse_from([
+        "rtorchdist",
+        "predict",
+        "lion.jpg",
+        "cat.png",
+        "-k",
+        "5",
+        "--model-name",
+        "resnet18",
+        "--model-dir",
+        "/srv/models",
+    ])
+    .unwrap();
+    assert_eq!(
+        cli.command,
+        Some(Command::Predict {
+            files: vec!["lion.jpg".to_string(), "cat.png".to_string()],
+            model_name: Some("resnet18".to_string()),
+            k: Some(5),
+            min_confidence: None,
+        })
+    );
+    assert_eq!(cli.config.model_dir.as_deref(), Some("/srv/models"));
+
+    assert_eq!(
+        Cli::try_parse_from(["rtorchdist", "check"])
+            .unwrap()
+            .command,
+        Some(Command::Check)
+    );
+    assert!(Cli::try_parse_from(["rtorchdist", "predict"]).is_err());
+    assert!(Cli::try_parse_from(["rtorchdist", "train"]).is_err());
+}
+
+#[actix_rt::test]
+async fn test_predict_fails_before_loading_models() {
+    let config = Config::default();
+    let files = vec!["tests/fixtures/does-not-exist.jpg".to_string()];
+    assert_eq!(
+        cli::predict(&config, &files, None, &PredictParams::default()).await,
+        2
+    );
+
+    let params = PredictParams {
+        k: None,
+        min_confidence: Some(2.0),
+    };
+    let files = vec!["tests/fixtures/lion.jpg".to_string()];
+    assert_eq!(cli::predict(&config, &files, None, &params).await, 2);
+}
@@ -0,0 +1,183 @@
+use rtorchdist::config::{Config, ConfigArgs, ConfigError};
+use std::collections::HashMap;
+
+const CONFIG: &str = r#"
+[server]
+bind = "127.0.0.1:9000"
+workers = 2
+keep_alive_secs = 0
+shutdown_timeout_secs = 10
+
+[models]
+dir = "/srv/models"
+
+[limits]
+max_bytes = 1048576
+allowed_formats = ["jpeg", "png"]
+
+[fetch]
+allowed_hosts = ["images.example.com"]
+
+[batching]
+max_batch_size = 16
+
+[predict]
+default_top_k = 3
+
+[logging]
+level = "debug"
+
+[features]
+batch_routes = false
+"#;
+
+fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+    vars.iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_config_from_toml() {
+    let config = Config::from_toml(CONFIG).unwrap();
+    assert_eq!(config.server.bind, "127.0.0.1:9000");
+    assert_eq!(config.server.workers, 2);
+    assert_eq!(config.server.keep_alive_secs, 0);
+    assert_eq!(config.server.shutdown_timeout_secs, 10);
+    assert_eq!(config.models.dir, "/srv/models");
+    assert_eq!(config.models.manifest_path(), "/srv/models/manifest.json");
+    assert_eq!(config.limits.max_bytes, 1048576);
+    assert_eq!(config.limits.allowed_formats, vec!["jpeg", "png"]);
+    assert_eq!(config.fetch.allowed_hosts, vec!["images.example.com"]);
+    assert_eq!(config.batching.max_batch_size, 16);
+    assert_eq!(config.batching.max_wait_ms, 5);
+    assert_eq!(config.predict.default_top_k, 3);
+    assert_eq!(config.predict.max_top_k, 20);
+    assert_eq!(config.logging.level, "debug");
+    assert!(!config.features.batch_routes);
+    // Settings missing from the file keep their defaults.
+    assert!(config.features.json_input);
+    assert_eq!(config.limits.max_width, Config::default().limits.max_width);
+    assert_eq!(config.validate(), Ok(()));
+
+    let error = Config::from_toml("[server]\nbnd = \"127.0.0.1:9000\"").unwrap_err();
+    assert!(
+        error.to_string().contains("unknown field `bnd`"),
+        "{}",
+        error
+    );
+    let error = Config::from_toml("[limits]\nmax_bytes = \"big\"").unwrap_err();
+    assert!(error.to_string().contains("invalid type"), "{}", error);
+}
+
+#[test]
+fn test_env_overrides_file_and_flags_override_env() {
+    let mut config = Config::from_toml(CONFIG).unwrap();
+    let vars = env(&[
+        ("BIND_ADDRESS", "127.0.0.1:9100"),
+        ("WORKERS", "4"),
+        ("BACKLOG", "64"),
+        ("SHUTDOWN_DELAY_SECS", "5"),
+        ("GRPC_BIND", "127.0.0.1:9101"),
+        ("MAX_UPLOAD_BYTES", "2048"),
+        ("FETCH_ALLOWED_HOSTS", "a.example.com, *.cdn.net"),
+        ("MAX_BATCH_WAIT_MS", "20"),
+        ("MAX_TOP_K", "5"),
+        ("DEFAULT_MIN_CONFIDENCE", "0.25"),
+        ("ENABLE_BATCH_ROUTES", "true"),
+        ("ENABLE_TF_SERVING_ROUTES", "false"),
+    ]);
+    config.apply_env(&|name| vars.get(name).cloned()).unwrap();
+    assert_eq!(config.server.bind, "127.0.0.1:9100");
+    assert_eq!(config.server.workers, 4);
+    assert_eq!(config.server.backlog, 64);
+    assert_eq!(config.server.shutdown_delay_secs, 5);
+    assert_eq!(config.server.shutdown_timeout_secs, 10);
+    assert_eq!(config.grpc.bind.as_deref(), Some("127.0.0.1:9101"));
+    assert_eq!(config.limits.max_bytes, 2048);
+    assert_eq!(
+        config.fetch.allowed_hosts,
+        vec!["a.example.com", "*.cdn.net"]
+    );
+    assert_eq!(config.batching.max_batch_size, 16);
+    assert_eq!(config.batching.max_wait_ms, 20);
+    assert_eq!(config.predict.default_top_k, 3);
+    assert_eq!(config.predict.max_top_k, 5);
+    assert_eq!(config.predict.default_min_confidence, 0.25);
+    assert!(config.features.batch_routes);
+    assert!(!config.features.tf_serving_routes);
+    assert_eq!(config.logging.level, "debug");
+
+    config.apply_args(&ConfigArgs {
+        bind: Some("127.0.0.1:9200".to_string()),
+        grpc_bind: Some("127.0.0.1:9201".to_string()),
+        log_level: Some("warn".to_string()),
+        ..ConfigArgs::default()
+    });
+    assert_eq!(config.server.bind, "127.0.0.1:9200");
+    assert_eq!(config.grpc.bind.as_deref(), Some("127.0.0.1:9201"));
+    assert_eq!(config.server.workers, 4);
+    assert_eq!(config.logging.level, "warn");
+
+    let vars = env(&[("WORKERS", "many"), ("FETCH_ALLOW_PRIVATE", "yes")]);
+    let ConfigError(errors) = config
+        .apply_env(&|name| vars.get(name).cloned())
+        .unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].starts_with("WORKERS=\"many\""), "{:?}", errors);
+    assert!(
+        errors[1].starts_with("FETCH_ALLOW_PRIVATE=\"yes\""),
+        "{:?}",
+        errors
+    );
+}
+
+#[test]
+fn test_defaults_ignore_the_environment() {
+    // Only `apply_env` reads the environment, the file and its defaults never do.
+    std::env::set_var("MAX_BATCH_SIZE", "64");
+    std::env::set_var("DEFAULT_TOP_K", "7");
+    let config = Config::from_toml("").unwrap();
+    std::env::remove_var("MAX_BATCH_SIZE");
+    std::env::remove_var("DEFAULT_TOP_K");
+    assert_eq!(config.batching.max_batch_size, 8);
+    assert_eq!(config.predict.default_top_k, 1);
+    assert_eq!(config, Config::default());
+}
+
+#[test]
+fn test_validate_reports_every_problem() {
+    let mut config = Config::from_toml(CONFIG).unwrap();
+    config.server.bind = "nowhere".to_string();
+    config.server.workers = 0;
+    config.grpc.bind = Some("nowhere".to_string());
+    config.models.path = Some("/does/not/exist.ot".to_string());
+    config.limits.max_bytes = 0;
+    config.limits.allowed_formats = vec!["jpeg".to_string(), "psd".to_string()];
+    config.batching.max_batch_size = 0;
+    config.predict.default_min_confidence = 2.0;
+    config.logging.level = "loud".to_string();
+    let error = config.validate().unwrap_err();
+    let message = error.to_string();
+    for expected in [
+        "server.bind",
+        "server.workers",
+        "grpc.bind",
+        "models.path",
+        "limits.max_bytes",
+        "unknown image format \"psd\"",
+        "batching.max_batch_size",
+        "predict.default_min_confidence",
+        "unknown log level \"loud\"",
+    ] {
+        assert!(message.contains(expected), "{}", message);
+    }
+    assert_eq!(error.0.len(), 9);
+
+    let mut config = Config::default();
+    config.batching.max_batch_size = 257;
+    assert_eq!(
+        config.validate().unwrap_err().0,
+        vec!["batching.max_batch_size: must be at most 256, got 257".to_string()]
+    );
+}
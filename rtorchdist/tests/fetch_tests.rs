@@ -0,0 +1,204 @@
+use rtorchdist::fetch::{fetch, is_private, FetchConfig, FetchError};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A local HTTP stand-in answering every request with `respond(path)`.
+fn serve(respond: fn(&str) -> Vec<u8>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                // Skip the headers.
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+                let _ = stream.write_all(&respond(path));
+            });
+        }
+    });
+    port
+}
+
+fn response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n",
+        status,
+        body.len(),
+        headers
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+fn stand_in(path: &str) -> Vec<u8> {
+    match path {
+        "/image.png" => response("200 OK", "Content-Type: image/png\r\n", b"\x89PNG image"),
+        "/redirect" => response("302 Found", "Location: /image.png\r\n", b""),
+        "/loop" => response("302 Found", "Location: /loop\r\n", b""),
+        "/elsewhere" => response(
+            "302 Found",
+            "Location: http://example.invalid/image.png\r\n",
+            b"",
+        ),
+        "/big" => response("200 OK", "", &[0u8; 4096]),
+        "/slow" => {
+            thread::sleep(Duration::from_millis(500));
+            response("200 OK", "", b"late")
+        }
+        _ => response("404 Not Found", "", b""),
+    }
+}
+
+fn config() -> FetchConfig {
+    FetchConfig {
+        allowed_hosts: vec!["127.0.0.1".to_string()],
+        allow_private: true,
+        connect_timeout_ms: 1000,
+        timeout_ms: 300,
+        max_bytes: 1024,
+        max_redirects: 2,
+    }
+}
+
+#[test]
+fn test_fetch_from_stand_in() {
+    let port = serve(stand_in);
+    let url = |path: &str| format!("http://127.0.0.1:{}{}", port, path);
+    let config = config();
+
+    assert_eq!(
+        fetch(&url("/image.png"), &config).unwrap(),
+        b"\x89PNG image".to_vec()
+    );
+    assert_eq!(
+        fetch(&url("/redirect"), &config).unwrap(),
+        b"\x89PNG image".to_vec()
+    );
+    assert_eq!(
+        fetch(&url("/loop"), &config),
+        Err(FetchError::TooManyRedirects(2))
+    );
+    // Every redirect target must be allowed too.
+    assert_eq!(
+        fetch(&url("/elsewhere"), &config),
+        Err(FetchError::HostNotAllowed("example.invalid".to_string()))
+    );
+    assert_eq!(
+        fetch(&url("/big"), &config),
+        Err(FetchError::TooLarge { limit: 1024 })
+    );
+    assert_eq!(fetch(&url("/slow"), &config), Err(FetchError::Timeout));
+    assert!(matches!(
+        fetch(&url("/missing"), &config),
+        Err(FetchError::Failed(_))
+    ));
+}
+
+#[test]
+fn test_fetch_deadline_covers_the_whole_download() {
+    // Sends the body a byte at a time, each well within any single read timeout.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        while reader.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 20\r\n\r\n");
+        for _ in 0..20 {
+            thread::sleep(Duration::from_millis(50));
+            if stream.write_all(b"x").is_err() {
+                break;
+            }
+        }
+    });
+    let started = Instant::now();
+    let url = format!("http://127.0.0.1:{}/trickle", port);
+    assert_eq!(fetch(&url, &config()), Err(FetchError::Timeout));
+    assert!(started.elapsed() < Duration::from_millis(900));
+}
+
+#[test]
+fn test_fetch_refuses_hosts_and_private_addresses() {
+    let port = serve(stand_in);
+    let url = format!("http://127.0.0.1:{}/image.png", port);
+
+    let public_only = FetchConfig {
+        allow_private: false,
+        ..config()
+    };
+    let error = fetch(&url, &public_only).unwrap_err();
+    assert_eq!(error, FetchError::PrivateAddress("127.0.0.1".to_string()));
+    assert_eq!(error.code(), "url_private_address");
+
+    let no_hosts = FetchConfig {
+        allowed_hosts: vec![],
+        ..config()
+    };
+    assert_eq!(
+        fetch(&url, &no_hosts),
+        Err(FetchError::HostNotAllowed("127.0.0.1".to_string()))
+    );
+    assert!(matches!(
+        fetch("file:///etc/passwd", &config()),
+        Err(FetchError::InvalidUrl(_))
+    ));
+}
+
+#[test]
+fn test_host_allowlist_and_private_ranges() {
+    let config = FetchConfig {
+        allowed_hosts: vec!["images.example.com".to_string(), "*.cdn.net".to_string()],
+        ..config()
+    };
+    assert!(config.host_allowed("images.example.com"));
+    assert!(config.host_allowed("Images.Example.com."));
+    assert!(config.host_allowed("a.b.cdn.net"));
+    assert!(!config.host_allowed("cdn.net"));
+    assert!(!config.host_allowed("example.com"));
+
+    for ip in [
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.16.0.1",
+        "192.168.1.1",
+        "169.254.169.254",
+        "100.64.0.1",
+        "0.0.0.0",
+        "::1",
+        "fd00::1",
+        "fe80::1",
+        "::ffff:10.0.0.1",
+        "198.18.0.1",
+        "198.19.255.255",
+        "240.0.0.1",
+        "255.255.255.255",
+        "64:ff9b::808:808",
+    ] {
+        assert!(is_private(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+    }
+    for ip in [
+        "8.8.8.8",
+        "1.1.1.1",
+        "198.20.0.1",
+        "223.255.255.1",
+        "2606:4700:4700::1111",
+    ] {
+        assert!(!is_private(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+    }
+}
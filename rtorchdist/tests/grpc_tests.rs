@@ -0,0 +1,216 @@
+#![cfg(feature = "grpc")]
+
+use actix_web::web;
+use rtorchdist::backend::{ModelBackend, ModelFormat};
+use rtorchdist::batch::BatchConfig;
+use rtorchdist::config::Config;
+use rtorchdist::grpc::proto::infer_parameter::ParameterChoice;
+use rtorchdist::grpc::proto::model_infer_request::{InferInputTensor, InferRequestedOutputTensor};
+use rtorchdist::grpc::proto::{InferParameter, InferTensorContents, ModelInferRequest};
+use rtorchdist::grpc::{
+    self, decode_raw_bytes, encode_raw_bytes, input_images, output_kinds, output_tensor,
+};
+use rtorchdist::health::{HealthConfig, ModelHealth};
+use rtorchdist::labels::Labels;
+use rtorchdist::logic::Model;
+use rtorchdist::preprocess::PreprocessConfig;
+use rtorchdist::registry::{ModelRegistry, ModelSpec};
+use rtorchdist::shutdown::Listener;
+use rtorchdist::tensor::Tensor;
+use rtorchdist::upload::UploadLimits;
+use rtorchdist::v2::{InputImages, Output, OutputData, OutputKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn input(datatype: &str, shape: Vec<i64>, contents: InferTensorContents) -> InferInputTensor {
+    InferInputTensor {
+        name: "input".to_string(),
+        datatype: datatype.to_string(),
+        shape,
+        contents: Some(contents),
+        ..InferInputTensor::default()
+    }
+}
+
+#[test]
+fn test_raw_bytes_round_trip() {
+    let raw = encode_raw_bytes(&[b"image".as_slice(), b"", b"png"]);
+    assert_eq!(&raw[..9], b"\x05\x00\x00\x00image");
+    assert_eq!(
+        decode_raw_bytes(&raw),
+        Ok(vec![b"image".to_vec(), Vec::new(), b"png".to_vec()])
+    );
+    assert_eq!(decode_raw_bytes(&[]), Ok(Vec::new()));
+    assert!(decode_raw_bytes(&raw[..raw.len() - 1]).is_err());
+    assert!(decode_raw_bytes(&[1, 0]).is_err());
+}
+
+#[test]
+fn test_typed_and_raw_inputs() {
+    let preprocess = PreprocessConfig::square(2);
+    let limits = UploadLimits::default();
+    let data: Vec<f32> = (0..12).map(|v| v as f32).collect();
+    let expected = || InputImages::Tensor {
+        data: data.clone(),
+        shape: [1, 3, 2, 2],
+    };
+
+    let typed = input(
+        "FP32",
+        vec![1, 3, 2, 2],
+        InferTensorContents {
+            fp32_contents: data.clone(),
+            ..InferTensorContents::default()
+        },
+    );
+    assert_eq!(
+        input_images(&typed, None, &preprocess, &limits),
+        Ok(expected())
+    );
+
+    let raw: Vec<u8> = data
+        .iter()
+        .flat_map(|v| (*v as f64).to_le_bytes())
+        .collect();
+    let fp64 = input("FP64", vec![3, 2, 2], InferTensorContents::default());
+    assert_eq!(
+        input_images(&fp64, Some(&raw), &preprocess, &limits),
+        Ok(expected())
+    );
+    assert!(input_images(&fp64, Some(&raw[..95]), &preprocess, &limits).is_err());
+
+    let bytes = input("BYTES", vec![2], InferTensorContents::default());
+    let raw = encode_raw_bytes(&[b"image".as_slice(), b"png"]);
+    assert_eq!(
+        input_images(&bytes, Some(&raw), &preprocess, &limits),
+        Ok(InputImages::Encoded(vec![
+            b"image".to_vec(),
+            b"png".to_vec()
+        ]))
+    );
+    let short = input(
+        "BYTES",
+        vec![2],
+        InferTensorContents {
+            bytes_contents: vec![b"image".to_vec()],
+            ..InferTensorContents::default()
+        },
+    );
+    let result = input_images(&short, None, &preprocess, &limits);
+    assert!(
+        result
+            .as_ref()
+            .is_err_and(|e| e.contains("needs 2 values, got 1")),
+        "{:?}",
+        result
+    );
+
+    let huge = input(
+        "FP32",
+        vec![i64::MAX, 3, 2, 2],
+        InferTensorContents::default(),
+    );
+    let result = input_images(&huge, None, &preprocess, &limits);
+    assert!(
+        result
+            .as_ref()
+            .is_err_and(|e| e.contains("byte request limit")),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_requested_outputs() {
+    let mut request = ModelInferRequest::default();
+    assert_eq!(output_kinds(&request), Ok(vec![OutputKind::Probabilities]));
+
+    let classification = |choice| InferRequestedOutputTensor {
+        name: "probabilities".to_string(),
+        parameters: vec![(
+            "classification".to_string(),
+            InferParameter {
+                parameter_choice: Some(choice),
+            },
+        )]
+        .into_iter()
+        .collect(),
+    };
+    request.outputs = vec![classification(ParameterChoice::Int64Param(3))];
+    assert_eq!(
+        output_kinds(&request),
+        Ok(vec![OutputKind::Classification(3)])
+    );
+    request.outputs = vec![classification(ParameterChoice::BoolParam(true))];
+    assert!(output_kinds(&request).is_err());
+}
+
+#[test]
+fn test_output_contents() {
+    let output = Output {
+        shape: vec![1, 2],
+        data: OutputData::Fp32(vec![0.25, 0.75]),
+    };
+    let (tensor, raw) = output_tensor(output.clone(), false);
+    assert_eq!(tensor.name, "probabilities");
+    assert_eq!(tensor.datatype, "FP32");
+    assert_eq!(tensor.shape, vec![1, 2]);
+    assert_eq!(tensor.contents.unwrap().fp32_contents, vec![0.25, 0.75]);
+    assert_eq!(raw, None);
+
+    let (tensor, raw) = output_tensor(output, true);
+    assert_eq!(tensor.contents, None);
+    let raw = raw.unwrap();
+    assert_eq!(&raw[4..], &0.75f32.to_le_bytes());
+
+    let output = Output {
+        shape: vec![1, 1],
+        data: OutputData::Bytes(vec!["0.75:1:cat".to_string()]),
+    };
+    let (_, raw) = output_tensor(output, true);
+    assert_eq!(
+        decode_raw_bytes(&raw.unwrap()),
+        Ok(vec![b"0.75:1:cat".to_vec()])
+    );
+}
+
+// Never runs, the server is stopped without requests.
+struct UnusedBackend;
+
+impl ModelBackend for UnusedBackend {
+    fn forward(&self, _: &Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        Err("not a real model".into())
+    }
+
+    fn format(&self) -> ModelFormat {
+        ModelFormat::Onnx
+    }
+}
+
+#[test]
+fn test_stop_waits_for_the_server_thread() {
+    let model = Model::new(
+        "unused",
+        Arc::new(UnusedBackend),
+        BatchConfig::default(),
+        Labels::numbered(2),
+        PreprocessConfig::default(),
+        ModelHealth::new(3),
+    );
+    let spec = ModelSpec::new("unused".to_string(), "unused.onnx".to_string());
+    let registry = ModelRegistry::from_models(
+        vec![(spec, model)],
+        None,
+        &BatchConfig::default(),
+        &HealthConfig::default(),
+    )
+    .unwrap();
+    let mut config = Config::default();
+    config.grpc.bind = Some("127.0.0.1:0".to_string());
+    config.server.workers = 1;
+    let server = grpc::start(web::Data::new(config), web::Data::new(registry)).unwrap();
+
+    let started = Instant::now();
+    assert!(Box::new(server).stop(Instant::now() + Duration::from_secs(10)));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,75 @@
+use rtorchdist::health::{HealthConfig, ModelHealth, ModelStatus};
+
+#[test]
+fn test_ready_after_self_check() {
+    let health = ModelHealth::new(3);
+    assert_eq!(health.status(), ModelStatus::Loading);
+    assert!(!health.report("resnet34").ready);
+
+    health.self_check_failed("no fixture image".to_string());
+    let report = health.report("resnet34");
+    assert_eq!(report.status, ModelStatus::SelfCheckFailed);
+    assert!(!report.ready);
+    assert_eq!(report.last_error.as_deref(), Some("no fixture image"));
+
+    health.self_check_passed();
+    let report = health.report("resnet34");
+    assert_eq!(report.name, "resnet34");
+    assert_eq!(report.status, ModelStatus::Ready);
+    assert!(report.ready);
+    assert_eq!(report.last_error, None);
+}
+
+#[test]
+fn test_repeated_failures_drop_readiness() {
+    let health = ModelHealth::new(3);
+    health.self_check_passed();
+
+    health.record_failure("shape mismatch".to_string());
+    health.record_failure("shape mismatch".to_string());
+    // A success in between resets the count.
+    health.record_success();
+    health.record_failure("shape mismatch".to_string());
+    health.record_failure("shape mismatch".to_string());
+    assert_eq!(health.status(), ModelStatus::Ready);
+
+    health.record_failure("shape mismatch".to_string());
+    let report = health.report("resnet34");
+    assert_eq!(report.status, ModelStatus::Failing);
+    assert!(!report.ready);
+    assert_eq!(report.consecutive_failures, 3);
+
+    health.record_success();
+    assert_eq!(health.status(), ModelStatus::Ready);
+    assert_eq!(health.report("resnet34").consecutive_failures, 0);
+
+    // Passing the self check again makes a failing model ready too.
+    for _ in 0..3 {
+        health.record_failure("shape mismatch".to_string());
+    }
+    assert_eq!(health.status(), ModelStatus::Failing);
+    health.self_check_passed();
+    assert_eq!(health.status(), ModelStatus::Ready);
+    assert_eq!(health.report("resnet34").consecutive_failures, 0);
+
+    // Failures before the self check don't make a model ready or failing.
+    let loading = ModelHealth::new(1);
+    loading.record_failure("oops".to_string());
+    loading.record_success();
+    assert_eq!(loading.status(), ModelStatus::Loading);
+}
+
+#[test]
+fn test_health_report_json() {
+    let health = ModelHealth::new(HealthConfig::default().max_consecutive_failures);
+    health.self_check_passed();
+    assert_eq!(
+        serde_json::to_value(health.report("resnet34")).unwrap(),
+        serde_json::json!({
+            "name": "resnet34",
+            "status": "ready",
+            "ready": true,
+            "consecutive_failures": 0,
+        })
+    );
+}
@@ -0,0 +1,55 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rtorchdist::input::{decode_base64, JsonImages, JsonPredictRequest};
+use rtorchdist::logic::PredictParams;
+
+#[test]
+fn test_decode_base64() {
+    let encoded = STANDARD.encode(b"\x89PNG rest of the file");
+    assert_eq!(
+        decode_base64(&encoded).unwrap(),
+        b"\x89PNG rest of the file".to_vec()
+    );
+    let data_uri = format!("data:image/png;base64,{}", encoded);
+    assert_eq!(
+        decode_base64(&data_uri).unwrap(),
+        b"\x89PNG rest of the file".to_vec()
+    );
+    // Line-wrapped base64 is accepted.
+    let wrapped = format!("{}\n{}", &encoded[..8], &encoded[8..]);
+    assert!(decode_base64(&wrapped).is_ok());
+    assert!(decode_base64("not base64!").is_err());
+    assert!(decode_base64("data:image/png,plain").is_err());
+}
+
+#[test]
+fn test_json_predict_request() -> Result<(), Box<dyn std::error::Error>> {
+    let request: JsonPredictRequest = serde_json::from_str(r#"{"image_b64": "YWJj", "k": 5}"#)?;
+    assert_eq!(request.images()?, JsonImages::Single(b"abc".to_vec()));
+    let query = PredictParams {
+        k: Some(2),
+        min_confidence: Some(0.1),
+    };
+    assert_eq!(
+        request.params(&query),
+        PredictParams {
+            k: Some(5),
+            min_confidence: Some(0.1)
+        }
+    );
+
+    let request: JsonPredictRequest = serde_json::from_str(r#"{"images": ["YWJj", "ZGVm"]}"#)?;
+    assert_eq!(
+        request.images()?,
+        JsonImages::List(vec![b"abc".to_vec(), b"def".to_vec()])
+    );
+
+    let request: JsonPredictRequest = serde_json::from_str(r#"{"images": ["YWJj", "???"]}"#)?;
+    assert!(request.images().unwrap_err().starts_with("images[1]"));
+    let request: JsonPredictRequest =
+        serde_json::from_str(r#"{"image_b64": "YWJj", "images": ["YWJj"]}"#)?;
+    assert!(request.images().is_err());
+    let request: JsonPredictRequest = serde_json::from_str(r#"{"k": 3}"#)?;
+    assert!(request.images().is_err());
+    Ok(())
+}
@@ -0,0 +1,157 @@
+use actix_web::middleware::from_fn;
+use actix_web::{get, http::StatusCode, test, web, App, HttpResponse};
+use futures::executor::block_on;
+use rtorchdist::backend::{ModelBackend, ModelFormat};
+use rtorchdist::batch::{BatchConfig, Batcher};
+use rtorchdist::health::{HealthConfig, ModelHealth};
+use rtorchdist::labels::Labels;
+use rtorchdist::logic::Model;
+use rtorchdist::metrics::{self, metrics, Stage};
+use rtorchdist::preprocess::PreprocessConfig;
+use rtorchdist::registry::{ModelRegistry, ModelSpec};
+use rtorchdist::routes::prometheus_metrics;
+use rtorchdist::tensor::Tensor;
+use std::sync::Arc;
+use std::time::Instant;
+
+#[get("/models/{name}/echo")]
+async fn echo(name: web::Path<String>) -> HttpResponse {
+    if name.as_str() == "missing" {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok().body(name.into_inner())
+}
+
+// Fails every forward pass, only its name and batches matter.
+struct UnusedBackend;
+
+impl ModelBackend for UnusedBackend {
+    fn forward(&self, _: &Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        Err("not a real model".into())
+    }
+
+    fn format(&self) -> ModelFormat {
+        ModelFormat::Onnx
+    }
+}
+
+fn registry(name: &str) -> ModelRegistry {
+    let model = Model::new(
+        name,
+        Arc::new(UnusedBackend),
+        BatchConfig::default(),
+        Labels::numbered(2),
+        PreprocessConfig::default(),
+        ModelHealth::new(3),
+    );
+    let spec = ModelSpec::new(name.to_string(), format!("{}.onnx", name));
+    ModelRegistry::from_models(
+        vec![(spec, model)],
+        None,
+        &BatchConfig::default(),
+        &HealthConfig::default(),
+    )
+    .unwrap()
+}
+
+#[actix_rt::test]
+async fn test_requests_are_counted_by_route_model_and_status() {
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(registry("resnet18")))
+            .wrap(from_fn(metrics::track_requests))
+            .service(echo)
+            .service(prometheus_metrics),
+    )
+    .await;
+    for uri in [
+        "/models/resnet18/echo",
+        "/models/resnet18/echo",
+        "/models/missing/echo",
+        "/models/made-up-1/echo",
+        "/nowhere",
+    ] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let req = test::TestRequest::get().uri("/metrics").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp
+        .headers()
+        .get("content-type")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain; version=0.0.4"));
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    for line in [
+        r#"rtorchdist_http_requests_total{model="resnet18",route="/models/{name}/echo",status="200"} 2"#,
+        // Names of models that don't exist all count as one label.
+        r#"rtorchdist_http_requests_total{model="unknown",route="/models/{name}/echo",status="200"} 1"#,
+        r#"rtorchdist_http_requests_total{model="unknown",route="/models/{name}/echo",status="404"} 1"#,
+        r#"rtorchdist_http_requests_total{model="",route="unmatched",status="404"} 1"#,
+        // The scrape itself is in flight.
+        "rtorchdist_http_requests_in_flight 1",
+    ] {
+        assert!(
+            body.lines().any(|l| l == line),
+            "{} missing in\n{}",
+            line,
+            body
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn test_stage_histograms_and_classes() {
+    let metrics = metrics();
+    metrics.observe(Stage::Decode, "stage-test", Instant::now());
+    metrics.observe(Stage::Decode, "stage-test", Instant::now());
+    metrics
+        .predicted_classes
+        .with_label_values(&["stage-test", "lion"])
+        .inc();
+    metrics
+        .model_load
+        .with_label_values(&["stage-test"])
+        .set(1.5);
+
+    let body = metrics.render();
+    for line in [
+        r#"rtorchdist_stage_duration_seconds_count{model="stage-test",stage="decode"} 2"#,
+        r#"rtorchdist_stage_duration_seconds_bucket{model="stage-test",stage="decode",le="+Inf"} 2"#,
+        r#"rtorchdist_predicted_class_total{class="lion",model="stage-test"} 1"#,
+        r#"rtorchdist_model_load_duration_seconds{model="stage-test"} 1.5"#,
+    ] {
+        assert!(
+            body.lines().any(|l| l == line),
+            "{} missing in\n{}",
+            line,
+            body
+        );
+    }
+    assert!(body.contains("# TYPE rtorchdist_stage_duration_seconds histogram"));
+}
+
+#[actix_rt::test]
+async fn test_batch_sizes_are_observed() {
+    let batcher = Batcher::start("batched", Arc::new(UnusedBackend), BatchConfig::default());
+    let image = Tensor::of_slice(&[0.0f32; 12]).view([3, 2, 2]);
+    assert!(block_on(batcher.submit(image)).is_err());
+
+    let body = metrics().render();
+    for line in [
+        r#"rtorchdist_batch_size_bucket{model="batched",le="1"} 1"#,
+        r#"rtorchdist_batch_size_bucket{model="batched",le="256"} 1"#,
+        r#"rtorchdist_batch_size_count{model="batched"} 1"#,
+    ] {
+        assert!(
+            body.lines().any(|l| l == line),
+            "{} missing in\n{}",
+            line,
+            body
+        );
+    }
+}
@@ -1,8 +1,15 @@
+#![cfg(feature = "torch")]
+
 use actix_web::web::Bytes;
 use futures::stream::Stream;
 use log::info;
+use rtorchdist::batch::BatchConfig;
+use rtorchdist::health::HealthConfig;
+use rtorchdist::labels::Labels;
 use rtorchdist::logic::self_check_predict;
 use rtorchdist::logic::tensor_device_cpu;
+use rtorchdist::logic::{rank, PredictConfig, PredictParams, Ranking};
+use rtorchdist::registry::{Manifest, ModelRegistry, ModelsConfig};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use test_log::test;
@@ -99,7 +106,17 @@ fn test_tensor_device_cpu() -> Result<(), Box<dyn std::error::Error>> {
 //tests self_check_predict()
 #[test]
 fn test_self_check_predict() {
-    let prediction = match self_check_predict() {
+    let config = ModelsConfig::default();
+    let registry = match Manifest::discover(&config).and_then(|manifest| {
+        ModelRegistry::load(manifest, &BatchConfig::default(), &HealthConfig::default())
+    }) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            return;
+        }
+    };
+    let prediction = match self_check_predict(&registry.default_model(), &config.self_check_image) {
         Ok(p) => p,
         Err(e) => {
             println!("Error: {:?}", e);
@@ -107,9 +124,8 @@ fn test_self_check_predict() {
         }
     };
     println!("TEST: Self check prediction: {:?}", prediction);
-    //assert Prediction { probabilities: [0.05008329823613167], classes: ["tarantula"] }
-    assert_eq!(prediction.probabilities[0], 0.05008329823613167);
-    assert_eq!(prediction.classes[0], "tarantula");
+    assert!(prediction.probabilities[0] > 0.0 && prediction.probabilities[0] <= 1.0);
+    assert_eq!(prediction.classes[0], "lion, king of beasts, Panthera leo");
 }
 
 /*
@@ -139,3 +155,44 @@ pub fn verify_image_contents(image_path: String, fixture_image_path: String) ->
 }
 
  */
+
+//tests the default, maximum and validation of the k and min_confidence parameters
+#[test]
+fn test_predict_params_resolve() {
+    let config = PredictConfig::default();
+    let ranking = |k, min_confidence| Ok(Ranking { k, min_confidence });
+    assert_eq!(PredictParams::default().resolve(&config), ranking(1, 0.0));
+    let params = PredictParams {
+        k: Some(5),
+        min_confidence: Some(0.1),
+    };
+    assert_eq!(params.resolve(&config), ranking(5, 0.1));
+    let params = PredictParams {
+        k: Some(10_000),
+        min_confidence: None,
+    };
+    assert_eq!(params.resolve(&config), ranking(20, 0.0));
+    let params = PredictParams {
+        k: None,
+        min_confidence: Some(1.5),
+    };
+    assert!(params.resolve(&config).is_err());
+    let config = PredictConfig {
+        default_top_k: 3,
+        max_top_k: 2,
+        default_min_confidence: 0.5,
+    };
+    assert_eq!(PredictParams::default().resolve(&config), ranking(2, 0.5));
+}
+
+//tests ranking classes of a probability tensor
+#[test]
+fn test_rank() {
+    let output = tch::Tensor::of_slice(&[0.1f32, 0.6, 0.05, 0.25]);
+    let top = rank(&output, 3, 0.0, &Labels::imagenet()).unwrap();
+    let indices: Vec<i64> = top.iter().map(|c| c.index).collect();
+    assert_eq!(indices, vec![1, 3, 0]);
+    assert_eq!(top[0].label, "goldfish, Carassius auratus");
+    let top = rank(&output, 3, 0.2, &Labels::imagenet()).unwrap();
+    assert_eq!(top.len(), 2);
+}
@@ -0,0 +1,130 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use rtorchdist::preprocess::{ChannelOrder, PreprocessConfig, ResizeStrategy, Size};
+use rtorchdist::registry::Manifest;
+use std::fs;
+
+/// Left half red, right half blue.
+fn split_image(width: u32, height: u32) -> DynamicImage {
+    let image = RgbImage::from_fn(width, height, |x, _| {
+        if x < width / 2 {
+            Rgb([255, 0, 0])
+        } else {
+            Rgb([0, 0, 255])
+        }
+    });
+    DynamicImage::ImageRgb8(image)
+}
+
+#[test]
+fn test_preprocess_config_from_manifest() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join("rtorchdist_preprocess_manifest");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("manifest.json");
+    fs::write(
+        &path,
+        r#"{"models": [
+            {"name": "resnet18", "path": "model/resnet18.ot"},
+            {"name": "inception-v3", "path": "model/inception-v3.ot"},
+            {"name": "detector", "path": "model/detector.pt", "preprocess": {
+                "resize": "letterbox", "size": [320, 240], "channel_order": "bgr",
+                "mean": [0.0, 0.0, 0.0], "std": [1.0, 1.0, 1.0], "filter": "nearest",
+                "pad_color": [114, 114, 114]
+            }}
+        ]}"#,
+    )?;
+    let manifest = Manifest::from_file(path.to_str().unwrap())?;
+    assert_eq!(manifest.models[0].preprocess(), PreprocessConfig::default());
+    assert_eq!(manifest.models[1].preprocess().size, Size::Square(299));
+    let detector = manifest.models[2].preprocess();
+    assert_eq!(detector.resize, ResizeStrategy::Letterbox);
+    assert_eq!(detector.size, Size::WidthHeight([320, 240]));
+    assert_eq!(detector.channel_order, ChannelOrder::Bgr);
+    assert!(detector.center_crop);
+    assert!(detector.validate().is_ok());
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_preprocess_validate() {
+    assert!(PreprocessConfig::default().validate().is_ok());
+    let config = PreprocessConfig {
+        resize_size: Some(Size::Square(256)),
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+    let config = PreprocessConfig {
+        resize_size: Some(Size::Square(200)),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+    let config = PreprocessConfig {
+        center_crop: false,
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+    let config = PreprocessConfig {
+        std: [0.2, 0.0, 0.2],
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_preprocess_fit() {
+    let image = split_image(400, 200);
+
+    // Shorter side to 224, then the center 224x224 crop keeps both halves.
+    let fitted = PreprocessConfig::default().fit(&image);
+    assert_eq!(fitted.dimensions(), (224, 224));
+    assert_eq!(fitted.get_pixel(0, 112), &Rgb([255, 0, 0]));
+    assert_eq!(fitted.get_pixel(223, 112), &Rgb([0, 0, 255]));
+
+    let stretch = PreprocessConfig {
+        resize: ResizeStrategy::Stretch,
+        size: Size::WidthHeight([64, 32]),
+        ..Default::default()
+    };
+    assert_eq!(stretch.fit(&image).dimensions(), (64, 32));
+
+    // A wide image is fitted to the width and padded above and below.
+    let letterbox = PreprocessConfig {
+        resize: ResizeStrategy::Letterbox,
+        size: Size::Square(100),
+        pad_color: [114, 114, 114],
+        ..Default::default()
+    };
+    let fitted = letterbox.fit(&image);
+    assert_eq!(fitted.dimensions(), (100, 100));
+    assert_eq!(fitted.get_pixel(50, 10), &Rgb([114, 114, 114]));
+    assert_eq!(fitted.get_pixel(10, 50), &Rgb([255, 0, 0]));
+    assert_eq!(fitted.get_pixel(90, 50), &Rgb([0, 0, 255]));
+}
+
+#[test]
+fn test_preprocess_normalize() {
+    let image = RgbImage::from_pixel(2, 1, Rgb([255, 0, 51]));
+    let config = PreprocessConfig {
+        mean: [0.5, 0.5, 0.5],
+        std: [0.5, 0.5, 0.5],
+        ..Default::default()
+    };
+    let data = config.normalize(&image);
+    assert_eq!(data.len(), 6);
+    // Channel planes: red, green, blue.
+    assert!((data[0] - 1.0).abs() < 1e-6);
+    assert!((data[2] + 1.0).abs() < 1e-6);
+    assert!((data[4] + 0.6).abs() < 1e-6);
+
+    let bgr = PreprocessConfig {
+        channel_order: ChannelOrder::Bgr,
+        scale: 1.0,
+        mean: [0.0, 0.0, 0.0],
+        std: [1.0, 1.0, 1.0],
+        ..Default::default()
+    };
+    assert_eq!(
+        bgr.normalize(&image),
+        vec![51.0, 51.0, 0.0, 0.0, 255.0, 255.0]
+    );
+}
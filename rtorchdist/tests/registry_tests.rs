@@ -0,0 +1,252 @@
+use rtorchdist::architecture::{Architecture, WeightMismatch};
+use rtorchdist::backend::{ModelBackend, ModelFormat};
+use rtorchdist::batch::BatchConfig;
+use rtorchdist::health::{HealthConfig, ModelHealth};
+use rtorchdist::labels::Labels;
+use rtorchdist::logic::Model;
+use rtorchdist::preprocess::PreprocessConfig;
+use rtorchdist::registry::{model_name, Manifest, ModelRegistry, ModelSpec};
+use rtorchdist::tensor::Tensor;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+#[test]
+fn test_model_name() {
+    assert_eq!(model_name("model/resnet34.ot"), "resnet34");
+    assert_eq!(model_name("/model/inception-v3.ot"), "inception-v3");
+}
+
+#[test]
+fn test_manifest_from_dir() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join("rtorchdist_registry_from_dir");
+    fs::create_dir_all(&dir)?;
+    for file in [
+        "vgg13.ot",
+        "alexnet.ot",
+        "scripted.pt",
+        "exported.onnx",
+        "README.md",
+    ] {
+        fs::write(dir.join(file), b"")?;
+    }
+    let manifest = Manifest::from_dir(dir.to_str().unwrap())?;
+    // Files of formats this build can't load are skipped.
+    let expected: Vec<(&str, ModelFormat)> = vec![
+        ("alexnet", ModelFormat::VarStore),
+        ("exported", ModelFormat::Onnx),
+        ("scripted", ModelFormat::TorchScript),
+        ("vgg13", ModelFormat::VarStore),
+    ]
+    .into_iter()
+    .filter(|(_, format)| format.is_available())
+    .collect();
+    let found: Vec<(&str, ModelFormat)> = manifest
+        .models
+        .iter()
+        .map(|m| (m.name.as_str(), m.format))
+        .collect();
+    assert_eq!(found, expected);
+    assert_eq!(manifest.default, None);
+    assert_eq!(
+        manifest.default_name().as_deref(),
+        found.first().map(|(name, _)| *name)
+    );
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_manifest_from_file() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join("rtorchdist_registry_from_file");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("manifest.json");
+    fs::write(
+        &path,
+        r#"{"default": "resnet18", "models": [
+            {"name": "resnet18", "path": "model/resnet18.ot"},
+            {"name": "classifier", "path": "model/custom.ot", "architecture": "mobilenet_v2", "num_classes": 10},
+            {"name": "research", "path": "model/resnet34.ot", "format": "torch_script"}
+        ]}"#,
+    )?;
+    let manifest = Manifest::from_file(path.to_str().unwrap())?;
+    assert_eq!(manifest.default.as_deref(), Some("resnet18"));
+    assert_eq!(manifest.default_name().as_deref(), Some("resnet18"));
+    let unnamed = Manifest {
+        default: None,
+        models: vec![
+            ModelSpec::new("alexnet".to_string(), "model/alexnet.ot".to_string()),
+            ModelSpec::new("resnet34".to_string(), "model/resnet34.ot".to_string()),
+        ],
+    };
+    assert_eq!(unnamed.default_name().as_deref(), Some("resnet34"));
+    assert_eq!(
+        manifest.models[0],
+        ModelSpec::new("resnet18".to_string(), "model/resnet18.ot".to_string())
+    );
+    assert_eq!(manifest.models[0].architecture()?, Architecture::Resnet18);
+    assert_eq!(
+        manifest.models[1].architecture()?,
+        Architecture::MobilenetV2
+    );
+    assert_eq!(manifest.models[1].num_classes, Some(10));
+    assert_eq!(manifest.models[1].num_classes(None)?, 10);
+    assert_eq!(manifest.models[0].num_classes(None)?, 1000);
+    assert_eq!(manifest.models[1].format, ModelFormat::VarStore);
+    assert_eq!(manifest.models[2].format, ModelFormat::TorchScript);
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_architecture_from_model_name() {
+    assert_eq!(
+        Architecture::from_model_name("resnet34"),
+        Some(Architecture::Resnet34)
+    );
+    assert_eq!(
+        Architecture::from_model_name("inception-v3"),
+        Some(Architecture::InceptionV3)
+    );
+    assert_eq!(
+        Architecture::from_model_name("squeezenet1_1"),
+        Some(Architecture::Squeezenet1_1)
+    );
+    assert_eq!(Architecture::from_model_name("custom"), None);
+    let spec = ModelSpec::new("custom".to_string(), "model/custom.ot".to_string());
+    assert!(spec.architecture().is_err());
+}
+
+#[test]
+fn test_weight_mismatch_report() {
+    let expected: HashMap<String, Vec<i64>> = vec![
+        ("conv1.weight".to_string(), vec![64, 3, 7, 7]),
+        ("fc.weight".to_string(), vec![1000, 512]),
+        ("layer1.0.conv1.weight".to_string(), vec![64, 64, 3, 3]),
+    ]
+    .into_iter()
+    .collect();
+    let found: HashMap<String, Vec<i64>> = vec![
+        ("conv1.weight".to_string(), vec![64, 3, 7, 7]),
+        ("fc.weight".to_string(), vec![10, 512]),
+        ("layer1.2.conv1.weight".to_string(), vec![64, 64, 3, 3]),
+    ]
+    .into_iter()
+    .collect();
+    let mismatch = WeightMismatch::compare("model/resnet34.ot", "resnet18", &expected, &found);
+    assert!(!mismatch.is_empty());
+    assert_eq!(mismatch.missing, vec!["layer1.0.conv1.weight"]);
+    assert_eq!(mismatch.unexpected, vec!["layer1.2.conv1.weight"]);
+    assert_eq!(
+        mismatch.to_string(),
+        "weights model/resnet34.ot do not fit architecture resnet18; \
+         1 missing variable(s): layer1.0.conv1.weight; \
+         1 unexpected variable(s): layer1.2.conv1.weight; \
+         1 variable(s) with the wrong shape: fc.weight [1000, 512] != [10, 512]"
+    );
+    assert!(
+        WeightMismatch::compare("model/resnet18.ot", "resnet18", &expected, &expected).is_empty()
+    );
+}
+
+#[test]
+fn test_labels_formats() -> Result<(), Box<dyn std::error::Error>> {
+    let labels = Labels::from_text("cat\ndog\nbird\n\n");
+    assert_eq!(labels.len(), 3);
+    assert_eq!(labels.name(1), "dog");
+    assert_eq!(labels.name(7), "7");
+
+    let labels = Labels::from_json(r#"["cat", "dog"]"#)?;
+    assert_eq!(
+        labels,
+        Labels::new(vec!["cat".to_string(), "dog".to_string()])
+    );
+
+    let labels = Labels::from_json(r#"{"1": "dog", "0": "cat", "2": "bird"}"#)?;
+    assert_eq!(labels.name(0), "cat");
+    assert_eq!(labels.name(2), "bird");
+
+    assert!(Labels::from_json(r#"{"0": "cat", "5": "dog"}"#).is_err());
+    assert!(Labels::from_json(r#"{"first": "cat"}"#).is_err());
+    let duplicate = Labels::from_json(r#"{"0": "cat", "01": "dog", "1": "bird"}"#)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(duplicate, r#"class ids "01" and "1" are both class 1"#);
+    assert_eq!(Labels::imagenet().len(), 1000);
+    Ok(())
+}
+
+#[test]
+fn test_labels_set_num_classes() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join("rtorchdist_registry_labels");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("labels.txt");
+    fs::write(&path, "cat\ndog\n")?;
+    let mut spec = ModelSpec::new("pets".to_string(), "model/pets.ot".to_string());
+    spec.labels = Some(path.to_string_lossy().to_string());
+    let labels = spec.load_labels()?;
+    assert_eq!(spec.num_classes(labels.as_ref())?, 2);
+    spec.num_classes = Some(3);
+    assert!(spec.num_classes(labels.as_ref()).is_err());
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+// Never runs, only the names of the models matter.
+struct UnusedBackend;
+
+impl ModelBackend for UnusedBackend {
+    fn forward(&self, _: &Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        Err("not a real model".into())
+    }
+
+    fn format(&self) -> ModelFormat {
+        ModelFormat::Onnx
+    }
+}
+
+#[test]
+fn test_manifest_and_registry_agree_on_the_default() {
+    let manifest = |default: Option<&str>, names: &[&str]| Manifest {
+        default: default.map(str::to_string),
+        models: names
+            .iter()
+            .map(|name| ModelSpec::new(name.to_string(), format!("model/{}.onnx", name)))
+            .collect(),
+    };
+    let registry = |manifest: &Manifest| {
+        let loaded = manifest
+            .models
+            .iter()
+            .map(|spec| {
+                let model = Model::new(
+                    &spec.name,
+                    Arc::new(UnusedBackend),
+                    BatchConfig::default(),
+                    Labels::numbered(2),
+                    PreprocessConfig::default(),
+                    ModelHealth::new(3),
+                );
+                (spec.clone(), model)
+            })
+            .collect();
+        ModelRegistry::from_models(
+            loaded,
+            manifest.default.clone(),
+            &BatchConfig::default(),
+            &HealthConfig::default(),
+        )
+    };
+    for (default, names, expected) in [
+        (None, vec!["vgg13", "alexnet"], "alexnet"),
+        (None, vec!["vgg13", "resnet34", "alexnet"], "resnet34"),
+        (Some("vgg13"), vec!["vgg13", "resnet34"], "vgg13"),
+    ] {
+        let manifest = manifest(default, &names);
+        assert_eq!(manifest.default_name().as_deref(), Some(expected));
+        assert_eq!(registry(&manifest).unwrap().default_name(), expected);
+    }
+    let missing = manifest(Some("vgg16"), &["vgg13"]);
+    assert_eq!(missing.default_name().as_deref(), Some("vgg16"));
+    assert!(registry(&missing).is_err());
+}
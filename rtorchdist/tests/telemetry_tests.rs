@@ -0,0 +1,226 @@
+use actix_web::middleware::from_fn;
+use actix_web::{get, test, App, HttpResponse};
+use rtorchdist::config::LoggingConfig;
+use rtorchdist::metrics::Stage;
+use rtorchdist::telemetry::{
+    self, export_layer, parse_traceparent, Exporter, SpanRecord, TraceContext, TracingConfig,
+};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tracing_subscriber::layer::SubscriberExt;
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const PARENT_ID: &str = "00f067aa0ba902b7";
+
+#[get("/hello")]
+async fn hello() -> HttpResponse {
+    HttpResponse::Ok().body("hello")
+}
+
+#[actix_rt::test]
+async fn test_traceparent_and_request_id_parsing() {
+    let traceparent = format!("00-{}-{}-01", TRACE_ID, PARENT_ID);
+    assert_eq!(
+        parse_traceparent(&traceparent),
+        Some((TRACE_ID.to_string(), PARENT_ID.to_string()))
+    );
+    for invalid in [
+        "",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+    ] {
+        assert_eq!(parse_traceparent(invalid), None, "{:?}", invalid);
+    }
+
+    let req = test::TestRequest::get()
+        .insert_header(("traceparent", traceparent.as_str()))
+        .insert_header(("X-Request-Id", "client-42"))
+        .to_http_request();
+    let context = TraceContext::from_headers(req.headers());
+    assert_eq!(context.request_id, "client-42");
+    assert_eq!(context.trace_id, TRACE_ID);
+    assert_eq!(context.parent_span_id.as_deref(), Some(PARENT_ID));
+
+    // Request ids that could forge log lines are replaced by the trace id.
+    let req = test::TestRequest::get()
+        .insert_header(("X-Request-Id", "a b\"c"))
+        .to_http_request();
+    let context = TraceContext::from_headers(req.headers());
+    assert_eq!(context.request_id, context.trace_id);
+    assert_eq!(context.trace_id.len(), 32);
+    assert_eq!(context.parent_span_id, None);
+}
+
+#[actix_rt::test]
+async fn test_request_id_is_returned() {
+    let app = test::init_service(
+        App::new()
+            .wrap(from_fn(telemetry::request_context))
+            .service(hello),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/hello")
+        .insert_header(("X-Request-Id", "client-42"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.headers().get("x-request-id").unwrap(), "client-42");
+
+    let req = test::TestRequest::get()
+        .uri("/nowhere")
+        .insert_header((
+            "traceparent",
+            format!("00-{}-{}-01", TRACE_ID, PARENT_ID).as_str(),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.headers().get("x-request-id").unwrap(), TRACE_ID);
+}
+
+/// A request span with a decode stage inside.
+fn request_span() {
+    let request = tracing::info_span!(
+        "request",
+        request_id = "client-42",
+        trace_id = TRACE_ID,
+        parent_span_id = PARENT_ID,
+        status = tracing::field::Empty,
+    );
+    request.in_scope(|| Stage::Decode.span("resnet18").in_scope(|| {}));
+    request.record("status", 200);
+}
+
+/// Runs `request_span`, exported as configured in `config`.
+fn record_spans(config: &TracingConfig) {
+    let (layer, worker) = export_layer(config).unwrap().unwrap();
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, request_span);
+    // Dropping the subscriber closed the exporter, which has flushed once it exits.
+    worker.join().unwrap();
+}
+
+#[actix_rt::test]
+async fn test_log_level_does_not_filter_exported_spans() {
+    let spans = std::env::temp_dir().join("rtorchdist_telemetry_level_spans.jsonl");
+    let log = std::env::temp_dir().join("rtorchdist_telemetry_level.log");
+    let _ = std::fs::remove_file(&spans);
+    let _ = std::fs::remove_file(&log);
+    let logging = LoggingConfig {
+        level: "warn".to_string(),
+        file: Some(log.to_str().unwrap().to_string()),
+        ..LoggingConfig::default()
+    };
+    let tracing = TracingConfig {
+        exporter: Exporter::File,
+        file: spans.to_str().unwrap().to_string(),
+        ..TracingConfig::default()
+    };
+    let (subscriber, worker) = telemetry::subscriber(&logging, &tracing).unwrap();
+    tracing::subscriber::with_default(subscriber, || {
+        request_span();
+        tracing::info!("func: test: not logged");
+        tracing::warn!("func: test: logged");
+    });
+    worker.unwrap().join().unwrap();
+
+    assert_eq!(std::fs::read_to_string(&spans).unwrap().lines().count(), 2);
+    let log = std::fs::read_to_string(&log).unwrap();
+    assert!(log.contains("func: test: logged"), "{}", log);
+    assert!(!log.contains("not logged"), "{}", log);
+}
+
+#[actix_rt::test]
+async fn test_file_exporter_writes_spans() {
+    let path = std::env::temp_dir().join("rtorchdist_telemetry_spans.jsonl");
+    let _ = std::fs::remove_file(&path);
+    let config = TracingConfig {
+        exporter: Exporter::File,
+        file: path.to_str().unwrap().to_string(),
+        ..TracingConfig::default()
+    };
+    record_spans(&config);
+
+    let spans: Vec<SpanRecord> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(spans.len(), 2);
+    let (decode, request) = (&spans[0], &spans[1]);
+    assert_eq!(decode.name, "decode");
+    assert_eq!(decode.attributes["model"], "resnet18");
+    assert_eq!(request.name, "request");
+    assert_eq!(request.attributes["request_id"], "client-42");
+    assert_eq!(request.attributes["status"], "200");
+    assert_eq!(request.parent_span_id.as_deref(), Some(PARENT_ID));
+    assert_eq!(
+        decode.parent_span_id.as_deref(),
+        Some(request.span_id.as_str())
+    );
+    for span in &spans {
+        assert_eq!(span.trace_id, TRACE_ID);
+        assert!(span.start_unix_nanos <= span.end_unix_nanos);
+    }
+}
+
+#[actix_rt::test]
+async fn test_otlp_exporter_posts_spans() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, bodies) = mpsc::channel();
+    // A local stand-in for an OTLP/HTTP collector.
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}");
+            sender.send((request_line, body)).unwrap();
+        }
+    });
+
+    let config = TracingConfig {
+        exporter: Exporter::Otlp,
+        otlp_endpoint: format!("http://127.0.0.1:{}/v1/traces", port),
+        service_name: "rtorchdist-test".to_string(),
+        ..TracingConfig::default()
+    };
+    record_spans(&config);
+
+    let (request_line, body) = bodies.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request_line.starts_with("POST /v1/traces "));
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let resource = &body["resourceSpans"][0];
+    assert_eq!(
+        resource["resource"]["attributes"][0]["value"]["stringValue"],
+        "rtorchdist-test"
+    );
+    let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+    assert_eq!(spans.len(), 2);
+    assert_eq!(spans[0]["name"], "decode");
+    assert_eq!(spans[0]["traceId"], TRACE_ID);
+    assert_eq!(spans[1]["name"], "request");
+    assert_eq!(spans[1]["parentSpanId"], PARENT_ID);
+    assert_eq!(spans[1]["kind"], 2);
+}
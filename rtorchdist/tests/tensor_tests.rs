@@ -0,0 +1,34 @@
+#![cfg(not(feature = "torch"))]
+
+use rtorchdist::tensor::{Kind, Tensor};
+
+#[test]
+fn test_view_stack_and_get() {
+    let t = Tensor::of_slice(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    assert_eq!(t.view([2, -1]).size(), vec![2, 3]);
+    assert!(t.f_view([4, -1]).is_err());
+
+    let stacked = Tensor::f_stack(&[t.view([2, 3]), t.view([2, 3])], 0).unwrap();
+    assert_eq!(stacked.size(), vec![2, 2, 3]);
+    assert_eq!(
+        Vec::<f32>::from(&stacked.get(1).get(1)),
+        vec![4.0, 5.0, 6.0]
+    );
+    assert!(Tensor::f_stack(&[t.view([2, 3]), t.view([3, 2])], 0).is_err());
+}
+
+#[test]
+fn test_softmax_and_topk() {
+    let logits = Tensor::of_slice(&[0.0, 2.0, 1.0, 3.0, 3.0, 0.0]).view([2, 3]);
+    let probabilities = logits.softmax(-1, Kind::Float);
+    for row in 0..2 {
+        let sum: f32 = Vec::<f32>::from(&probabilities.get(row)).iter().sum();
+        assert!((sum - 1.0).abs() < 1e-6);
+    }
+
+    let (values, indices) = probabilities.f_topk(2, -1, true, true).unwrap();
+    assert_eq!(values.size(), vec![2, 2]);
+    assert_eq!(Vec::<i64>::from(&indices.get(0)), vec![1, 2]);
+    assert_eq!(Vec::<i64>::from(&indices.get(1)), vec![0, 1]);
+    assert!(logits.f_softmax(0, Kind::Float).is_err());
+}
@@ -0,0 +1,63 @@
+use rtorchdist::logic::{ClassScore, Prediction};
+use rtorchdist::tfserving::{instance_image, PredictRequest, PredictResponse};
+use serde_json::json;
+
+fn request(body: serde_json::Value) -> PredictRequest {
+    serde_json::from_value(body).unwrap()
+}
+
+#[test]
+fn test_b64_instances() {
+    let body = request(json!({
+        "signature_name": "serving_default",
+        "instances": [
+            {"b64": "aW1hZ2U="},
+            {"image_bytes": {"b64": "data:image/png;base64,cG5n"}},
+        ],
+    }));
+    assert_eq!(body.images(), Ok(vec![b"image".to_vec(), b"png".to_vec()]));
+    assert_eq!(instance_image(&json!({"b64": "cG5n"})), Ok(b"png".to_vec()));
+
+    for (body, error) in [
+        (json!({"instances": []}), "at least one image"),
+        (json!({}), "at least one image"),
+        (json!({"inputs": [{"b64": "cG5n"}]}), "\"inputs\" format"),
+        (json!({"instances": [[0.1, 0.2]]}), "instances[0]: must be"),
+        (
+            json!({"instances": [{"b64": "cG5n"}, {"b64": "cG5n", "key": 1}]}),
+            "instances[1]: must be",
+        ),
+        (json!({"instances": [{"b64": 42}]}), "instances[0]: must be"),
+        (
+            json!({"instances": [{"b64": "%%"}]}),
+            "instances[0]: invalid base64",
+        ),
+    ] {
+        let result = request(body).images();
+        assert!(
+            result.as_ref().is_err_and(|e| e.contains(error)),
+            "{:?} should fail with {:?}",
+            result,
+            error
+        );
+    }
+}
+
+#[test]
+fn test_predictions_shape() {
+    let response = PredictResponse {
+        predictions: vec![Prediction::new(vec![ClassScore {
+            index: 291,
+            label: "lion".to_string(),
+            probability: 0.5,
+        }])],
+    };
+    assert_eq!(
+        serde_json::to_value(&response).unwrap(),
+        json!({"predictions": [{
+            "probabilities": [0.5],
+            "classes": ["lion"],
+            "top": [{"index": 291, "label": "lion", "probability": 0.5}],
+        }]})
+    );
+}
@@ -0,0 +1,109 @@
+use actix_web::http::StatusCode;
+use image::codecs::gif::GifEncoder;
+use image::{DynamicImage, Frame, ImageFormat, RgbImage, RgbaImage};
+use rtorchdist::upload::{check_image, decode_image, frame_count, UploadError, UploadLimits};
+use std::io::Cursor;
+
+fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+    let image = DynamicImage::ImageRgb8(RgbImage::new(width, height));
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, format).unwrap();
+    bytes.into_inner()
+}
+
+fn animated_gif(frames: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        for _ in 0..frames {
+            encoder
+                .encode_frame(Frame::new(RgbaImage::new(4, 4)))
+                .unwrap();
+        }
+    }
+    bytes
+}
+
+fn limits() -> UploadLimits {
+    UploadLimits {
+        max_bytes: 1024 * 1024,
+        max_width: 1000,
+        max_height: 1000,
+        max_pixels: 250_000,
+        max_frames: 2,
+        allowed_formats: vec!["jpeg".to_string(), "png".to_string(), "gif".to_string()],
+    }
+}
+
+#[test]
+fn test_check_image_detects_format_from_magic_bytes() {
+    let limits = limits();
+    assert_eq!(
+        check_image(&encode(8, 8, ImageFormat::Png), &limits),
+        Ok(ImageFormat::Png)
+    );
+    assert_eq!(
+        check_image(&encode(8, 8, ImageFormat::Jpeg), &limits),
+        Ok(ImageFormat::Jpeg)
+    );
+    assert_eq!(
+        check_image(&encode(8, 8, ImageFormat::Bmp), &limits),
+        Err(UploadError::UnsupportedFormat(Some("bmp".to_string())))
+    );
+    let error = check_image(b"not an image at all", &limits).unwrap_err();
+    assert_eq!(error, UploadError::UnsupportedFormat(None));
+    assert_eq!(error.status_code(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(error.code(), "unsupported_media_type");
+}
+
+#[test]
+fn test_check_image_limits() {
+    let limits = limits();
+    let error = check_image(&encode(1001, 10, ImageFormat::Png), &limits).unwrap_err();
+    assert_eq!(
+        error,
+        UploadError::ImageTooLarge {
+            width: 1001,
+            height: 10
+        }
+    );
+    assert_eq!(error.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+    // Within the width and height limits, but too many pixels.
+    assert!(check_image(&encode(600, 600, ImageFormat::Png), &limits).is_err());
+
+    let small = UploadLimits {
+        max_bytes: 16,
+        ..limits.clone()
+    };
+    let error = check_image(&encode(8, 8, ImageFormat::Png), &small).unwrap_err();
+    assert_eq!(error, UploadError::PayloadTooLarge { limit: 16 });
+    assert_eq!(error.code(), "payload_too_large");
+
+    let gif = animated_gif(3);
+    assert_eq!(frame_count(&gif, ImageFormat::Gif), 3);
+    assert_eq!(
+        check_image(&gif, &limits),
+        Err(UploadError::TooManyFrames {
+            frames: 3,
+            limit: 2
+        })
+    );
+    assert_eq!(check_image(&animated_gif(2), &limits), Ok(ImageFormat::Gif));
+    assert_eq!(
+        frame_count(&encode(8, 8, ImageFormat::Png), ImageFormat::Png),
+        1
+    );
+}
+
+#[test]
+fn test_decode_image() {
+    let limits = limits();
+    let image = decode_image(&encode(12, 7, ImageFormat::Png), &limits).unwrap();
+    assert_eq!((image.width(), image.height()), (12, 7));
+    // A valid header followed by garbage fails to decode.
+    let mut truncated = encode(12, 7, ImageFormat::Png);
+    truncated.truncate(40);
+    let error = decode_image(&truncated, &limits).unwrap_err();
+    assert_eq!(error.code(), "invalid_image");
+    assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,188 @@
+use actix_web::http::StatusCode;
+use actix_web::{test, App, ResponseError};
+use rtorchdist::backend::ModelFormat;
+use rtorchdist::preprocess::PreprocessConfig;
+use rtorchdist::upload::UploadLimits;
+use rtorchdist::v2::{
+    self, InferenceRequest, InputImages, ModelMetadata, OutputKind, TensorMetadata, V2Error,
+};
+use serde_json::json;
+
+fn request(input: serde_json::Value) -> InferenceRequest {
+    serde_json::from_value(json!({ "id": "42", "inputs": [input] })).unwrap()
+}
+
+#[actix_rt::test]
+async fn test_model_metadata() {
+    let metadata = ModelMetadata::new(
+        "resnet34",
+        ModelFormat::TorchScript,
+        &PreprocessConfig::square(32),
+        1000,
+    );
+    assert_eq!(metadata.platform, "pytorch_torchscript");
+    assert_eq!(
+        metadata.inputs,
+        vec![TensorMetadata {
+            name: "input".to_string(),
+            datatype: "FP32".to_string(),
+            shape: vec![-1, 3, 32, 32],
+        }]
+    );
+    assert_eq!(metadata.outputs[0].shape, vec![-1, 1000]);
+}
+
+#[actix_rt::test]
+async fn test_tensor_and_bytes_inputs() {
+    let preprocess = PreprocessConfig::square(2);
+    let limits = UploadLimits::default();
+    // Nested and flat data are both row-major.
+    let nested = request(json!({
+        "name": "input",
+        "datatype": "FP32",
+        "shape": [1, 3, 2, 2],
+        "data": [[[[0, 1], [2, 3]], [[4, 5], [6, 7]], [[8, 9], [10, 11]]]],
+    }));
+    let flat = request(json!({
+        "name": "input",
+        "datatype": "FP64",
+        "shape": [3, 2, 2],
+        "data": (0..12).collect::<Vec<i32>>(),
+    }));
+    let expected = InputImages::Tensor {
+        data: (0..12).map(|v| v as f32).collect(),
+        shape: [1, 3, 2, 2],
+    };
+    assert_eq!(nested.images(&preprocess, &limits), Ok(expected));
+    assert_eq!(
+        flat.images(&preprocess, &limits).unwrap(),
+        nested.images(&preprocess, &limits).unwrap()
+    );
+
+    let bytes = request(json!({
+        "name": "input",
+        "datatype": "BYTES",
+        "shape": [2],
+        "data": ["aW1hZ2U=", "data:image/png;base64,cG5n"],
+    }));
+    assert_eq!(
+        bytes.images(&preprocess, &limits),
+        Ok(InputImages::Encoded(vec![
+            b"image".to_vec(),
+            b"png".to_vec()
+        ]))
+    );
+
+    for (input, error) in [
+        (
+            json!({"name": "pixels", "datatype": "FP32", "shape": [3, 2, 2], "data": vec![0; 12]}),
+            "unknown input",
+        ),
+        (
+            json!({"name": "input", "datatype": "FP32", "shape": [1, 3, 2, 2], "data": vec![0; 11]}),
+            "needs 12 values, got 11",
+        ),
+        (
+            json!({"name": "input", "datatype": "FP32", "shape": [1, 3, 4, 1], "data": vec![0; 12]}),
+            "doesn't match the model input [-1, 3, 2, 2]",
+        ),
+        (
+            json!({"name": "input", "datatype": "FP32", "shape": [3, 2, 2], "data": vec!["a"; 12]}),
+            "must be numbers",
+        ),
+        (
+            json!({"name": "input", "datatype": "INT8", "shape": [3, 2, 2], "data": vec![0; 12]}),
+            "unsupported datatype",
+        ),
+        (
+            json!({"name": "input", "datatype": "BYTES", "shape": [1], "data": ["%%"]}),
+            "data[0]: invalid base64",
+        ),
+        // Shapes whose size overflows or that can't fit in a request are refused before
+        // anything is allocated for them.
+        (
+            json!({"name": "input", "datatype": "FP32", "shape": [i64::MAX, 3, 2, 2], "data": [0]}),
+            "don't fit in the 10485760 byte request limit, at most 873813",
+        ),
+        (
+            json!({"name": "input", "datatype": "BYTES", "shape": [i64::MAX, 1], "data": ["aW1hZ2U="]}),
+            "don't fit in the 10485760 byte request limit",
+        ),
+    ] {
+        let result = request(input).images(&preprocess, &limits);
+        assert!(
+            result.as_ref().is_err_and(|e| e.contains(error)),
+            "{:?} should fail with {:?}",
+            result,
+            error
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn test_check_len_overflow() {
+    assert_eq!(v2::check_len(&[2, 3, 2, 2], 24), Ok(()));
+    let result = v2::check_len(&[1 << 40, 3, 1 << 40, 1 << 40], 12);
+    assert!(
+        result
+            .as_ref()
+            .is_err_and(|e| e.contains("holds too many values")),
+        "{:?}",
+        result
+    );
+}
+
+#[actix_rt::test]
+async fn test_requested_outputs() {
+    let mut request = request(json!({
+        "name": "input", "datatype": "BYTES", "shape": [0], "data": [],
+    }));
+    assert_eq!(request.outputs(), Ok(vec![OutputKind::Probabilities]));
+
+    request.outputs = serde_json::from_value(json!([
+        {"name": "probabilities"},
+        {"name": "probabilities", "parameters": {"classification": 3}},
+    ]))
+    .unwrap();
+    assert_eq!(
+        request.outputs(),
+        Ok(vec![
+            OutputKind::Probabilities,
+            OutputKind::Classification(3)
+        ])
+    );
+
+    request.outputs = serde_json::from_value(json!([{"name": "logits"}])).unwrap();
+    assert!(request.outputs().is_err());
+    request.outputs = serde_json::from_value(json!([
+        {"name": "probabilities", "parameters": {"classification": 0}},
+    ]))
+    .unwrap();
+    assert!(request.outputs().is_err());
+}
+
+#[actix_rt::test]
+async fn test_errors_and_liveness() {
+    let error = V2Error::bad_request("expected 1 input, got 2");
+    assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+    let body = actix_web::body::to_bytes(error.error_response().into_body())
+        .await
+        .unwrap();
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+        json!({"error": "expected 1 input, got 2"})
+    );
+
+    let app = test::init_service(
+        App::new()
+            .service(v2::server_live)
+            .service(v2::server_metadata),
+    )
+    .await;
+    let req = test::TestRequest::get().uri("/v2/health/live").to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body, json!({"live": true}));
+    let req = test::TestRequest::get().uri("/v2").to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["name"], "rtorchdist");
+}
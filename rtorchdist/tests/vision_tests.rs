@@ -1,3 +1,5 @@
+#![cfg(feature = "torch")]
+
 /*
 Taken from here to ensure I have proper install
 https://github.com/LaurentMazare/tch-rs/blob/main/tests/vision_tests.rs
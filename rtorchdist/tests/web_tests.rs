@@ -1,11 +1,28 @@
-use actix_web::{http::StatusCode, test, App};
-use rtorchdist::routes::index;
+use actix_multipart::Multipart;
+use actix_web::{get, http::StatusCode, post, test, web, App, HttpResponse};
+use rtorchdist::backend::{ModelBackend, ModelFormat};
+use rtorchdist::batch::BatchConfig;
+use rtorchdist::config::Config;
+use rtorchdist::error::ApiError;
+use rtorchdist::health::{HealthConfig, ModelHealth, ModelStatus};
+use rtorchdist::input;
+use rtorchdist::labels::Labels;
+use rtorchdist::logic::files;
+use rtorchdist::logic::{Model, PredictParams};
+use rtorchdist::preprocess::PreprocessConfig;
+use rtorchdist::registry::{ModelRegistry, ModelSpec};
+use rtorchdist::routes::{
+    check_image_prediction, check_image_upload, index, predict_model_batch, query_config, readyz,
+};
+use rtorchdist::tensor::Tensor;
+use serde_json::json;
+use std::sync::Arc;
 
 #[actix_rt::test]
 async fn test_index() {
-    let mut app = test::init_service(App::new().service(index)).await;
+    let app = test::init_service(App::new().service(index)).await;
     let req = test::TestRequest::get().uri("/").to_request();
-    let resp = test::call_service(&mut app, req).await;
+    let resp = test::call_service(&app, req).await;
 
     assert_eq!(resp.status(), StatusCode::OK);
 
@@ -13,3 +30,327 @@ async fn test_index() {
     let expected_body = "Send an image payload using curl with the following command:\ncurl -X POST -H \"Content-Type: multipart/form-data\" -F \"image=@/path/to/your/image.jpg\" http://127.0.0.1:8080/predict";
     assert_eq!(response_body, expected_body);
 }
+
+// Echoes the field name, filename and size of every uploaded file.
+#[post("/echo_files")]
+async fn echo_files(payload: Multipart) -> Result<HttpResponse, ApiError> {
+    let uploaded = files::read_files(payload, 16).await?;
+    let summary: Vec<_> = uploaded
+        .iter()
+        .map(|f| json!({ "field": f.field, "filename": f.filename, "size": f.bytes.len() }))
+        .collect();
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+fn multipart_body(boundary: &str, parts: &[(&str, Option<&str>, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, filename, data) in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        let disposition = match filename {
+            Some(filename) => format!(
+                "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
+                name, filename
+            ),
+            None => format!("Content-Disposition: form-data; name=\"{}\"\r\n", name),
+        };
+        body.extend_from_slice(disposition.as_bytes());
+        body.extend_from_slice(b"Content-Type: application/octet-stream\r\n\r\n");
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+#[actix_rt::test]
+async fn test_read_files_reads_every_field() {
+    let app = test::init_service(App::new().service(echo_files)).await;
+    let boundary = "rtorchdistboundary";
+    let body = multipart_body(
+        boundary,
+        &[
+            ("first", Some("lion.jpg"), b"abc"),
+            ("second", None, b"defgh"),
+        ],
+    );
+    let req = test::TestRequest::post()
+        .uri("/echo_files")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(body)
+        .to_request();
+    let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        resp,
+        json!([
+            { "field": "first", "filename": "lion.jpg", "size": 3 },
+            { "field": "second", "filename": null, "size": 5 },
+        ])
+    );
+}
+
+#[actix_rt::test]
+async fn test_read_files_rejects_oversized_upload() {
+    let app = test::init_service(App::new().service(echo_files)).await;
+    let boundary = "rtorchdistboundary";
+    let body = multipart_body(
+        boundary,
+        &[
+            ("first", Some("a.jpg"), &[0u8; 10]),
+            ("second", Some("b.jpg"), &[0u8; 10]),
+        ],
+    );
+    let req = test::TestRequest::post()
+        .uri("/echo_files")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(body)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[get("/echo_params")]
+async fn echo_params(params: web::Query<PredictParams>) -> HttpResponse {
+    HttpResponse::Ok().json(params.into_inner())
+}
+
+#[actix_rt::test]
+async fn test_errors_share_one_json_shape() {
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(Config::default()))
+            .app_data(query_config())
+            .service(echo_params)
+            .service(check_image_upload),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/echo_params?k=abc")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["code"], "invalid_params");
+
+    let boundary = "rtorchdistboundary";
+    let req = test::TestRequest::post()
+        .uri("/check_image_upload")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", boundary),
+        ))
+        .set_payload(multipart_body(boundary, &[]))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["code"], "invalid_multipart");
+    assert!(body["message"].is_string());
+}
+
+// Echoes the size of a raw request body.
+#[post("/echo_body")]
+async fn echo_body(payload: web::Payload) -> Result<HttpResponse, ApiError> {
+    let body = input::read_body(payload, 8).await?;
+    Ok(HttpResponse::Ok().json(json!({ "size": body.len() })))
+}
+
+#[actix_rt::test]
+async fn test_read_body_is_bounded() {
+    let app = test::init_service(App::new().service(echo_body)).await;
+    let req = test::TestRequest::post()
+        .uri("/echo_body")
+        .insert_header(("content-type", "image/png"))
+        .set_payload(vec![0u8; 8])
+        .to_request();
+    let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp, json!({ "size": 8 }));
+
+    let req = test::TestRequest::post()
+        .uri("/echo_body")
+        .insert_header(("content-type", "image/png"))
+        .set_payload(vec![0u8; 9])
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["code"], "payload_too_large");
+}
+
+// Scores class 1 highest for every image in the batch.
+struct ConstantBackend;
+
+impl ModelBackend for ConstantBackend {
+    fn forward(&self, images: &Tensor) -> Result<Tensor, Box<dyn std::error::Error>> {
+        let batch_size = images.size()[0];
+        let logits: Vec<f32> = (0..batch_size).flat_map(|_| vec![0.0, 4.0, 1.0]).collect();
+        Ok(Tensor::of_slice(&logits).view([batch_size, 3]))
+    }
+
+    fn format(&self) -> ModelFormat {
+        ModelFormat::Onnx
+    }
+}
+
+fn constant_registry() -> ModelRegistry {
+    let model = Model::new(
+        "constant",
+        Arc::new(ConstantBackend),
+        BatchConfig::default(),
+        Labels::new(vec!["cat".into(), "lion".into(), "dog".into()]),
+        PreprocessConfig::default(),
+        ModelHealth::new(3),
+    );
+    let spec = ModelSpec::new("constant".to_string(), "constant.onnx".to_string());
+    ModelRegistry::from_models(
+        vec![(spec, model)],
+        None,
+        &BatchConfig::default(),
+        &HealthConfig::default(),
+    )
+    .unwrap()
+}
+
+#[actix_rt::test]
+async fn test_batch_route_reports_each_image() {
+    let lion = std::fs::read("tests/fixtures/lion.jpg").unwrap();
+    let mut config = Config::default();
+    config.limits.max_bytes = lion.len() * 3 / 2;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(constant_registry()))
+            .app_data(web::Data::new(config))
+            .app_data(query_config())
+            .service(predict_model_batch),
+    )
+    .await;
+    let boundary = "rtorchdistboundary";
+    let batch = |parts: &[(&str, Option<&str>, &[u8])]| {
+        test::TestRequest::post()
+            .uri("/models/constant/predict/batch?k=1")
+            .insert_header((
+                "content-type",
+                format!("multipart/form-data; boundary={}", boundary),
+            ))
+            .set_payload(multipart_body(boundary, parts))
+            .to_request()
+    };
+
+    // A bad image fails on its own, the other images of the batch are still predicted.
+    let req = batch(&[
+        ("good", Some("lion.jpg"), &lion),
+        ("bad", Some("notes.txt"), b"not an image"),
+    ]);
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["status"], "success");
+    let items = body["result"].as_array().unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0]["field"], "good");
+    assert_eq!(items[0]["status"], "success");
+    assert_eq!(items[0]["result"]["top"][0]["label"], "lion");
+    assert_eq!(items[1]["field"], "bad");
+    assert_eq!(items[1]["filename"], "notes.txt");
+    assert_eq!(items[1]["status"], "error");
+    assert_eq!(items[1]["code"], "unsupported_media_type");
+    assert!(items[1]["message"].is_string());
+
+    // The byte limit bounds all files together, each of these fits on its own.
+    let req = batch(&[
+        ("first", Some("lion.jpg"), &lion),
+        ("second", Some("lion.jpg"), &lion),
+    ]);
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["code"], "payload_too_large");
+}
+
+#[actix_rt::test]
+async fn test_readyz_only_reads_the_status() {
+    let registry = web::Data::new(constant_registry());
+    let app = test::init_service(App::new().app_data(registry.clone()).service(readyz)).await;
+    let probe = || test::TestRequest::get().uri("/readyz").to_request();
+    let health = || registry.get("constant").unwrap().health().status();
+
+    // The probe doesn't run the self check of a loading model.
+    assert_eq!(
+        test::call_service(&app, probe()).await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(health(), ModelStatus::Loading);
+
+    registry.startup_check("tests/fixtures/missing.jpg");
+    assert_eq!(health(), ModelStatus::SelfCheckFailed);
+    assert!(registry.recheck_failed("tests/fixtures/missing.jpg"));
+    assert!(!registry.recheck_failed("tests/fixtures/lion.jpg"));
+    assert_eq!(
+        test::call_service(&app, probe()).await.status(),
+        StatusCode::OK
+    );
+
+    // The probe leaves a failing model failing, the recheck makes it ready again.
+    let model = registry.get("constant").unwrap();
+    for _ in 0..3 {
+        model.health().record_failure("shape mismatch".to_string());
+    }
+    assert_eq!(
+        test::call_service(&app, probe()).await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    assert_eq!(health(), ModelStatus::Failing);
+    assert!(!registry.recheck_failed("tests/fixtures/lion.jpg"));
+    assert_eq!(health(), ModelStatus::Ready);
+    assert_eq!(model.health().report("constant").consecutive_failures, 0);
+    assert_eq!(
+        test::call_service(&app, probe()).await.status(),
+        StatusCode::OK
+    );
+}
+
+#[actix_rt::test]
+async fn test_check_image_prediction_reports_the_last_self_check() {
+    let registry = web::Data::new(constant_registry());
+    let app = test::init_service(
+        App::new()
+            .app_data(registry.clone())
+            .service(check_image_prediction),
+    )
+    .await;
+    let check = || {
+        test::TestRequest::get()
+            .uri("/check_image_prediction")
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, check()).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["code"], "self_check_failed");
+
+    registry.startup_check("tests/fixtures/lion.jpg");
+    let resp = test::call_service(&app, check()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["result"]["name"], "constant");
+    assert_eq!(body["result"]["status"], "ready");
+
+    registry.startup_check("tests/fixtures/missing.jpg");
+    let resp = test::call_service(&app, check()).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .contains("self check of model constant failed"));
+}